@@ -1,5 +1,8 @@
+use crate::error::Error;
+
 use std::convert::{TryFrom, TryInto};
 use std::env::var as env_var;
+use std::fmt;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::{Read, Seek, SeekFrom, Write};
@@ -7,8 +10,8 @@ use std::path::Path;
 use std::path::PathBuf;
 use std::str;
 
+use chrono::{DateTime, Duration, Utc};
 use dirs;
-use failure::{err_msg, Error};
 use indexmap::map::Entry;
 use indexmap::IndexMap;
 use path_abs::PathFile;
@@ -29,15 +32,27 @@ impl Profile {
 
     fn set_sts_credentials(&mut self, creds: StsCreds) -> Result<(), Error> {
         if !self.is_sts_credentials() {
-            bail!("Profile is not STS. Cannot set STS credentials");
+            return Err(Error::Config(
+                "Profile is not STS. Cannot set STS credentials".to_string(),
+            ));
         }
 
+        // An expiration from a previous run is meaningless for the new credentials
+        self.0.remove("aws_session_expiration");
+
         for (key, value) in Profile::from(creds).0 {
             self.0.insert(key, value);
         }
 
         Ok(())
     }
+
+    fn expiration(&self) -> Option<DateTime<Utc>> {
+        self.0
+            .get("aws_session_expiration")
+            .and_then(|expiration| DateTime::parse_from_rfc3339(expiration).ok())
+            .map(|expiration| expiration.with_timezone(&Utc))
+    }
 }
 
 impl From<StsCreds> for Profile {
@@ -51,6 +66,10 @@ impl From<StsCreds> for Profile {
         );
         map.insert("aws_session_token".to_string(), creds.aws_session_token);
 
+        if let Some(expiration) = creds.aws_session_expiration {
+            map.insert("aws_session_expiration".to_string(), expiration);
+        }
+
         Profile(map)
     }
 }
@@ -60,6 +79,7 @@ pub struct StsCreds {
     aws_access_key_id: String,
     aws_secret_access_key: String,
     aws_session_token: String,
+    aws_session_expiration: Option<String>,
 }
 
 impl TryFrom<Profile> for StsCreds {
@@ -70,15 +90,16 @@ impl TryFrom<Profile> for StsCreds {
             aws_access_key_id: profile
                 .0
                 .remove("aws_access_key_id")
-                .ok_or_else(|| err_msg("No aws_access_key_id found"))?,
+                .ok_or_else(|| Error::Config("No aws_access_key_id found".to_string()))?,
             aws_secret_access_key: profile
                 .0
                 .remove("aws_secret_access_key")
-                .ok_or_else(|| err_msg("No aws_secret_access_key found"))?,
+                .ok_or_else(|| Error::Config("No aws_secret_access_key found".to_string()))?,
             aws_session_token: profile
                 .0
                 .remove("aws_session_token")
-                .ok_or_else(|| err_msg("No aws_secret_access_key found"))?,
+                .ok_or_else(|| Error::Config("No aws_session_token found".to_string()))?,
+            aws_session_expiration: profile.0.remove("aws_session_expiration"),
         })
     }
 }
@@ -89,6 +110,36 @@ impl From<rusoto_sts::Credentials> for StsCreds {
             aws_access_key_id: creds.access_key_id,
             aws_secret_access_key: creds.secret_access_key,
             aws_session_token: creds.session_token,
+            aws_session_expiration: Some(creds.expiration),
+        }
+    }
+}
+
+/// What is known about how long the STS credentials of a profile stay valid
+#[derive(Debug, PartialEq)]
+pub enum Freshness {
+    /// The profile has no STS credentials
+    Missing,
+    /// The profile has STS credentials, but their expiration was not recorded
+    Unknown,
+    Expires(DateTime<Utc>),
+}
+
+impl fmt::Display for Freshness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Freshness::Missing => write!(f, "missing"),
+            Freshness::Unknown => write!(f, "unknown expiration"),
+            Freshness::Expires(expiration) if *expiration <= Utc::now() => {
+                write!(f, "expired {}", expiration.format("%Y-%m-%d %H:%M:%S UTC"))
+            }
+            Freshness::Expires(expiration) => {
+                write!(
+                    f,
+                    "valid until {}",
+                    expiration.format("%Y-%m-%d %H:%M:%S UTC")
+                )
+            }
         }
     }
 }
@@ -110,6 +161,33 @@ impl Profiles {
         Ok(())
     }
 
+    /// Whether the named profile lacks STS credentials that stay valid for at least `refresh_window`
+    pub fn needs_refresh(&self, name: &str, refresh_window: Duration) -> bool {
+        match self.freshness(name) {
+            Freshness::Expires(expiration) => expiration - Utc::now() < refresh_window,
+            Freshness::Missing | Freshness::Unknown => true,
+        }
+    }
+
+    pub fn freshness(&self, name: &str) -> Freshness {
+        match self.0.get(name) {
+            Some(profile) if profile.is_sts_credentials() => match profile.expiration() {
+                Some(expiration) => Freshness::Expires(expiration),
+                None => Freshness::Unknown,
+            },
+            _ => Freshness::Missing,
+        }
+    }
+
+    /// The profiles in the credentials file at `path`, without creating it if it does not exist
+    pub fn read(path: &Path) -> Result<Self, Error> {
+        if path.exists() {
+            Profiles::read_as_ini(File::open(path)?)
+        } else {
+            Ok(Profiles::default())
+        }
+    }
+
     fn read_as_ini<R>(reader: R) -> Result<Self, Error>
     where
         R: Read,
@@ -133,22 +211,33 @@ pub struct CredentialsStore {
 
 impl CredentialsStore {
     pub fn new() -> Result<CredentialsStore, Error> {
+        CredentialsStore::path()?.try_into()
+    }
+
+    /// The location of the AWS credentials file
+    pub fn path() -> Result<PathBuf, Error> {
         match env_var("AWS_SHARED_CREDENTIALS_FILE") {
-            Ok(path) => PathBuf::from(path),
-            Err(_) => CredentialsStore::default_profile_location()?,
+            Ok(path) => Ok(PathBuf::from(path)),
+            Err(_) => CredentialsStore::default_profile_location(),
         }
-        .try_into()
     }
 
     pub fn save(&mut self) -> Result<(), Error> {
         info!("Saving AWS credentials");
+
+        // The new contents may be shorter than the old ones
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+
         self.profiles.write_as_ini(&mut self.file)
     }
 
     fn default_profile_location() -> Result<PathBuf, Error> {
         match dirs::home_dir() {
             Some(home_dir) => Ok(home_dir.join(".aws").join("credentials")),
-            None => bail!("The environment variable HOME must be set."),
+            None => Err(Error::Config(
+                "The environment variable HOME must be set.".to_string(),
+            )),
         }
     }
 }
@@ -258,6 +347,7 @@ foo=bar";
             aws_access_key_id: "ACCESS_KEY".to_string(),
             aws_secret_access_key: "SECRET_ACCESS_KEY".to_string(),
             aws_session_token: "SESSION_TOKEN".to_string(),
+            aws_session_expiration: None,
         };
 
         let mut profile: Profile = creds.into();
@@ -324,6 +414,7 @@ foo=bar";
                     aws_access_key_id: "NEW_ACCESS_KEY".to_string(),
                     aws_secret_access_key: "NEW_SECRET_ACCESS_KEY".to_string(),
                     aws_session_token: "NEW_SESSION_TOKEN".to_string(),
+                    aws_session_expiration: None,
                 },
             )
             .unwrap();
@@ -353,6 +444,7 @@ foo=bar";
                     aws_access_key_id: "NEW_ACCESS_KEY".to_string(),
                     aws_secret_access_key: "NEW_SECRET_ACCESS_KEY".to_string(),
                     aws_session_token: "NEW_SESSION_TOKEN".to_string(),
+                    aws_session_expiration: None,
                 },
             )
             .unwrap();
@@ -387,6 +479,7 @@ foo=bar";
                 aws_access_key_id: "NEW_ACCESS_KEY".to_string(),
                 aws_secret_access_key: "NEW_SECRET_ACCESS_KEY".to_string(),
                 aws_session_token: "NEW_SESSION_TOKEN".to_string(),
+                aws_session_expiration: None,
             })
             .unwrap_err();
 
@@ -437,6 +530,7 @@ foo=bar
                 aws_access_key_id: "NEW_ACCESS_KEY".to_string(),
                 aws_secret_access_key: "NEW_SECRET_ACCESS_KEY".to_string(),
                 aws_session_token: "NEW_SESSION_TOKEN".to_string(),
+                aws_session_expiration: None,
             })
             .unwrap();
 
@@ -492,6 +586,7 @@ aws_session_token=SESSION_TOKEN"
                     aws_access_key_id: String::from("ACCESS_KEY2"),
                     aws_secret_access_key: String::from("SECRET_ACCESS_KEY2"),
                     aws_session_token: String::from("SESSION_TOKEN2"),
+                    aws_session_expiration: None,
                 },
             )
             .unwrap();
@@ -516,4 +611,114 @@ aws_session_token=SESSION_TOKEN2\r
 "
         );
     }
+
+    #[test]
+    fn update_sts_credentials_with_expiration() {
+        let profiles_ini = "[example]
+aws_access_key_id=ACCESS_KEY
+aws_secret_access_key=SECRET_ACCESS_KEY
+aws_session_token=SESSION_TOKEN
+aws_session_expiration=2021-01-01T00:00:00Z
+foo=bar";
+
+        let mut profiles = Profiles::read_as_ini(profiles_ini.as_bytes()).unwrap();
+
+        profiles
+            .set_sts_credentials(
+                "example".to_string(),
+                StsCreds {
+                    aws_access_key_id: "NEW_ACCESS_KEY".to_string(),
+                    aws_secret_access_key: "NEW_SECRET_ACCESS_KEY".to_string(),
+                    aws_session_token: "NEW_SESSION_TOKEN".to_string(),
+                    aws_session_expiration: Some("2021-01-01T01:00:00Z".to_string()),
+                },
+            )
+            .unwrap();
+
+        assert_eq!(
+            profiles.0["example"].0["aws_session_expiration"],
+            "2021-01-01T01:00:00Z"
+        );
+
+        profiles
+            .set_sts_credentials(
+                "example".to_string(),
+                StsCreds {
+                    aws_access_key_id: "NEW_ACCESS_KEY".to_string(),
+                    aws_secret_access_key: "NEW_SECRET_ACCESS_KEY".to_string(),
+                    aws_session_token: "NEW_SESSION_TOKEN".to_string(),
+                    aws_session_expiration: None,
+                },
+            )
+            .unwrap();
+
+        assert!(!profiles.0["example"]
+            .0
+            .contains_key("aws_session_expiration"));
+    }
+
+    #[test]
+    fn needs_refresh() {
+        let profiles_ini = format!(
+            "[fresh]
+aws_access_key_id=ACCESS_KEY
+aws_secret_access_key=SECRET_ACCESS_KEY
+aws_session_token=SESSION_TOKEN
+aws_session_expiration={}
+[expiring]
+aws_access_key_id=ACCESS_KEY
+aws_secret_access_key=SECRET_ACCESS_KEY
+aws_session_token=SESSION_TOKEN
+aws_session_expiration={}
+[unknown]
+aws_access_key_id=ACCESS_KEY
+aws_secret_access_key=SECRET_ACCESS_KEY
+aws_session_token=SESSION_TOKEN
+[static]
+aws_access_key_id=ACCESS_KEY
+aws_secret_access_key=SECRET_ACCESS_KEY
+aws_session_expiration={}",
+            (Utc::now() + Duration::hours(1)).to_rfc3339(),
+            (Utc::now() + Duration::minutes(1)).to_rfc3339(),
+            (Utc::now() + Duration::hours(1)).to_rfc3339(),
+        );
+
+        let profiles = Profiles::read_as_ini(profiles_ini.as_bytes()).unwrap();
+        let refresh_window = Duration::minutes(10);
+
+        assert!(!profiles.needs_refresh("fresh", refresh_window));
+        assert!(profiles.needs_refresh("expiring", refresh_window));
+        assert!(profiles.needs_refresh("unknown", refresh_window));
+        assert!(profiles.needs_refresh("static", refresh_window));
+        assert!(profiles.needs_refresh("missing", refresh_window));
+
+        assert!(matches!(profiles.freshness("fresh"), Freshness::Expires(_)));
+        assert_eq!(profiles.freshness("unknown"), Freshness::Unknown);
+        assert_eq!(profiles.freshness("static"), Freshness::Missing);
+        assert_eq!(profiles.freshness("missing"), Freshness::Missing);
+    }
+
+    #[test]
+    fn display_freshness() {
+        let expiration = DateTime::parse_from_rfc3339("2021-01-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert_eq!(
+            Freshness::Expires(expiration).to_string(),
+            "expired 2021-01-01 00:00:00 UTC"
+        );
+        assert_eq!(Freshness::Missing.to_string(), "missing");
+    }
+
+    #[test]
+    fn read_missing_profiles() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("credentials");
+
+        let profiles = Profiles::read(&path).unwrap();
+
+        assert_eq!(profiles, Profiles::default());
+        assert!(!path.exists());
+    }
 }
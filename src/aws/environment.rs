@@ -0,0 +1,163 @@
+use crate::error::Error;
+
+use std::str::FromStr;
+
+use indexmap::IndexMap;
+use rusoto_sts::Credentials;
+
+/// The environment variables the AWS CLI and SDKs read credentials from
+pub fn variables(credentials: &Credentials, region: Option<&str>) -> Vec<(&'static str, String)> {
+    let mut variables = vec![
+        ("AWS_ACCESS_KEY_ID", credentials.access_key_id.clone()),
+        (
+            "AWS_SECRET_ACCESS_KEY",
+            credentials.secret_access_key.clone(),
+        ),
+        ("AWS_SESSION_TOKEN", credentials.session_token.clone()),
+        ("AWS_CREDENTIAL_EXPIRATION", credentials.expiration.clone()),
+    ];
+
+    if let Some(region) = region {
+        variables.push(("AWS_REGION", region.to_string()));
+    }
+
+    variables
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Sh,
+    Fish,
+    PowerShell,
+    Dotenv,
+    Json,
+}
+
+impl Format {
+    /// Render `variables` as statements that set them, in this format
+    pub fn render(self, variables: &[(&str, String)]) -> Result<String, Error> {
+        let statement: fn(&str, &str) -> String = match self {
+            Format::Sh => {
+                |key, value| format!("export {}='{}'\n", key, value.replace('\'', r"'\''"))
+            }
+            Format::Fish => |key, value| {
+                format!(
+                    "set -gx {} '{}';\n",
+                    key,
+                    value.replace('\\', r"\\").replace('\'', r"\'")
+                )
+            },
+            Format::PowerShell => {
+                |key, value| format!("$Env:{} = '{}'\n", key, value.replace('\'', "''"))
+            }
+            Format::Dotenv => |key, value| {
+                format!(
+                    "{}=\"{}\"\n",
+                    key,
+                    value.replace('\\', r"\\").replace('"', r#"\""#)
+                )
+            },
+            Format::Json => {
+                let map = variables.iter().cloned().collect::<IndexMap<_, _>>();
+                return Ok(format!("{}\n", serde_json::to_string_pretty(&map)?));
+            }
+        };
+
+        Ok(variables
+            .iter()
+            .map(|(key, value)| statement(key, value))
+            .collect())
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sh" | "bash" | "zsh" => Ok(Format::Sh),
+            "fish" => Ok(Format::Fish),
+            "powershell" | "pwsh" => Ok(Format::PowerShell),
+            "dotenv" => Ok(Format::Dotenv),
+            "json" => Ok(Format::Json),
+            _ => Err(Error::Config(format!("Unknown format {}", s))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock_variables() -> Vec<(&'static str, String)> {
+        vec![
+            ("AWS_ACCESS_KEY_ID", "ACCESS_KEY".to_string()),
+            (
+                "AWS_SECRET_ACCESS_KEY",
+                r#"it's a "secret" \o/"#.to_string(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn render_sh() {
+        assert_eq!(
+            Format::Sh.render(&mock_variables()).unwrap(),
+            r#"export AWS_ACCESS_KEY_ID='ACCESS_KEY'
+export AWS_SECRET_ACCESS_KEY='it'\''s a "secret" \o/'
+"#
+        );
+    }
+
+    #[test]
+    fn render_fish() {
+        assert_eq!(
+            Format::Fish.render(&mock_variables()).unwrap(),
+            r#"set -gx AWS_ACCESS_KEY_ID 'ACCESS_KEY';
+set -gx AWS_SECRET_ACCESS_KEY 'it\'s a "secret" \\o/';
+"#
+        );
+    }
+
+    #[test]
+    fn render_powershell() {
+        assert_eq!(
+            Format::PowerShell.render(&mock_variables()).unwrap(),
+            r#"$Env:AWS_ACCESS_KEY_ID = 'ACCESS_KEY'
+$Env:AWS_SECRET_ACCESS_KEY = 'it''s a "secret" \o/'
+"#
+        );
+    }
+
+    #[test]
+    fn render_dotenv() {
+        assert_eq!(
+            Format::Dotenv.render(&mock_variables()).unwrap(),
+            r#"AWS_ACCESS_KEY_ID="ACCESS_KEY"
+AWS_SECRET_ACCESS_KEY="it's a \"secret\" \\o/"
+"#
+        );
+    }
+
+    #[test]
+    fn render_json() {
+        assert_eq!(
+            Format::Json.render(&mock_variables()).unwrap(),
+            r#"{
+  "AWS_ACCESS_KEY_ID": "ACCESS_KEY",
+  "AWS_SECRET_ACCESS_KEY": "it's a \"secret\" \\o/"
+}
+"#
+        );
+    }
+
+    #[test]
+    fn parse_format() {
+        assert_eq!("zsh".parse::<Format>().unwrap(), Format::Sh);
+        assert_eq!("pwsh".parse::<Format>().unwrap(), Format::PowerShell);
+        assert_eq!(
+            "cmd".parse::<Format>().unwrap_err().to_string(),
+            "Unknown format cmd"
+        );
+    }
+}
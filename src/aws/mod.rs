@@ -1,2 +1,4 @@
 pub mod credentials;
+pub mod environment;
+pub mod process;
 pub mod role;
@@ -0,0 +1,132 @@
+use crate::cache;
+use crate::error::Error;
+
+use std::path::PathBuf;
+
+use chrono::{DateTime, Duration, Utc};
+use rusoto_sts::Credentials;
+use serde::{Deserialize, Serialize};
+
+/// The document an AWS `credential_process` is expected to print
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ProcessCredentials {
+    pub version: u8,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: String,
+    pub expiration: String,
+}
+
+impl ProcessCredentials {
+    pub fn needs_refresh(&self, refresh_window: Duration) -> bool {
+        match DateTime::parse_from_rfc3339(&self.expiration) {
+            Ok(expiration) => expiration.with_timezone(&Utc) - Utc::now() < refresh_window,
+            Err(_) => true,
+        }
+    }
+}
+
+impl From<Credentials> for ProcessCredentials {
+    fn from(creds: Credentials) -> Self {
+        ProcessCredentials {
+            version: 1,
+            access_key_id: creds.access_key_id,
+            secret_access_key: creds.secret_access_key,
+            session_token: creds.session_token,
+            expiration: creds.expiration,
+        }
+    }
+}
+
+/// Credentials handed out by `credential_process`, kept between invocations
+#[derive(Debug)]
+pub struct ProcessCache {
+    dir: PathBuf,
+}
+
+impl ProcessCache {
+    pub fn new() -> Result<ProcessCache, Error> {
+        cache::dir().map(Into::into)
+    }
+
+    pub fn get(&self, organization: &str, profile: &str) -> Option<ProcessCredentials> {
+        cache::read(&self.path(organization, profile))
+    }
+
+    pub fn set(
+        &self,
+        organization: &str,
+        profile: &str,
+        creds: &ProcessCredentials,
+    ) -> Result<(), Error> {
+        cache::write(&self.path(organization, profile), creds)
+    }
+
+    fn path(&self, organization: &str, profile: &str) -> PathBuf {
+        self.dir
+            .join(organization)
+            .join(format!("{}.json", profile))
+    }
+}
+
+impl From<PathBuf> for ProcessCache {
+    fn from(dir: PathBuf) -> Self {
+        ProcessCache { dir }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile;
+
+    fn mock_credentials(expiration: DateTime<Utc>) -> ProcessCredentials {
+        ProcessCredentials {
+            version: 1,
+            access_key_id: "ACCESS_KEY".to_string(),
+            secret_access_key: "SECRET_ACCESS_KEY".to_string(),
+            session_token: "SESSION_TOKEN".to_string(),
+            expiration: expiration.to_rfc3339(),
+        }
+    }
+
+    #[test]
+    fn serialize_credentials() {
+        let creds = ProcessCredentials {
+            version: 1,
+            access_key_id: "ACCESS_KEY".to_string(),
+            secret_access_key: "SECRET_ACCESS_KEY".to_string(),
+            session_token: "SESSION_TOKEN".to_string(),
+            expiration: "2021-01-01T00:00:00Z".to_string(),
+        };
+
+        assert_eq!(
+            serde_json::to_string(&creds).unwrap(),
+            r#"{"Version":1,"AccessKeyId":"ACCESS_KEY","SecretAccessKey":"SECRET_ACCESS_KEY","SessionToken":"SESSION_TOKEN","Expiration":"2021-01-01T00:00:00Z"}"#
+        );
+    }
+
+    #[test]
+    fn needs_refresh() {
+        let refresh_window = Duration::minutes(10);
+
+        assert!(!mock_credentials(Utc::now() + Duration::hours(1)).needs_refresh(refresh_window));
+        assert!(mock_credentials(Utc::now() + Duration::minutes(1)).needs_refresh(refresh_window));
+    }
+
+    #[test]
+    fn cache_roundtrip() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let cache = ProcessCache::from(tempdir.path().to_path_buf());
+
+        assert_eq!(cache.get("mock_org", "example"), None);
+
+        let creds = mock_credentials(Utc::now());
+        cache.set("mock_org", "example", &creds).unwrap();
+
+        assert_eq!(cache.get("mock_org", "example"), Some(creds));
+        assert_eq!(cache.get("other_org", "example"), None);
+    }
+}
@@ -1,13 +1,24 @@
+use crate::error::Error;
+use crate::retry::{backoff, MAX_RETRIES};
+
 use std::str;
 use std::str::FromStr;
 
-use failure::Error;
+use glob::Pattern;
 use rusoto_core::request::HttpClient;
-use rusoto_core::Region;
+use rusoto_core::{Region, RusotoError};
 use rusoto_credential::StaticProvider;
 use rusoto_sts::{AssumeRoleWithSAMLRequest, AssumeRoleWithSAMLResponse, Sts, StsClient};
+use tokio::time::sleep;
+
+/// The shortest session STS allows, in seconds
+const MIN_DURATION_SECONDS: i64 = 900;
+/// The longest session STS allows for any role, in seconds
+const MAX_DURATION_SECONDS: i64 = 43200;
+/// The longest session every role allows, as roles cannot have a shorter maximum
+const DEFAULT_DURATION_SECONDS: i64 = 3600;
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Role {
     pub provider_arn: String,
     pub role_arn: String,
@@ -20,64 +31,280 @@ impl FromStr for Role {
         let splitted: Vec<&str> = s.split(',').collect();
 
         match splitted.len() {
-            0 | 1 => bail!("Not enough elements in {}", s),
+            0 | 1 => Err(Error::Saml(format!("Not enough elements in {}", s))),
             2 => Ok(Role {
                 provider_arn: String::from(splitted[0]),
                 role_arn: String::from(splitted[1]),
             }),
-            _ => bail!("Too many elements in {}", s),
+            _ => Err(Error::Saml(format!("Too many elements in {}", s))),
+        }
+    }
+}
+
+/// The AWS partition a role is in, which decides the STS endpoint to use
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Partition {
+    Aws,
+    AwsUsGov,
+    AwsCn,
+}
+
+impl FromStr for Partition {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aws" => Ok(Partition::Aws),
+            "aws-us-gov" => Ok(Partition::AwsUsGov),
+            "aws-cn" => Ok(Partition::AwsCn),
+            _ => Err(Error::Saml(format!("Unknown AWS partition {}", s))),
+        }
+    }
+}
+
+impl Partition {
+    fn of(region: &Region) -> Self {
+        if region.name().starts_with("us-gov-") {
+            Partition::AwsUsGov
+        } else if region.name().starts_with("cn-") {
+            Partition::AwsCn
+        } else {
+            Partition::Aws
+        }
+    }
+
+    /// The region to call STS in: the default region if it is in this partition
+    pub fn sts_region(self) -> Region {
+        let default_region = Region::default();
+
+        if Partition::of(&default_region) == self {
+            default_region
+        } else {
+            match self {
+                Partition::Aws => Region::UsEast1,
+                Partition::AwsUsGov => Region::UsGovWest1,
+                Partition::AwsCn => Region::CnNorth1,
+            }
         }
     }
 }
 
+/// The parts of a role ARN, `arn:<partition>:iam::<account>:role/<path>/<name>`
+struct RoleArn<'a> {
+    partition: &'a str,
+    account_id: &'a str,
+    /// The path and name, such as `team/deployer`
+    path_name: &'a str,
+    name: &'a str,
+}
+
+impl<'a> RoleArn<'a> {
+    fn parse(arn: &'a str) -> Result<Self, Error> {
+        let invalid = || Error::Saml(format!("Invalid role ARN {}", arn));
+
+        let parts: Vec<&str> = arn.splitn(6, ':').collect();
+        let (partition, account_id, resource) = match parts[..] {
+            ["arn", partition, "iam", "", account_id, resource] => {
+                (partition, account_id, resource)
+            }
+            _ => return Err(invalid()),
+        };
+
+        let path_name = match resource.strip_prefix("role/") {
+            Some(path_name) if !path_name.is_empty() && !path_name.ends_with('/') => path_name,
+            _ => return Err(invalid()),
+        };
+
+        Ok(RoleArn {
+            partition,
+            account_id,
+            path_name,
+            name: path_name.rsplit('/').next().unwrap_or(path_name),
+        })
+    }
+}
+
 impl Role {
+    /// The name of the role, without its path
     pub fn role_name(&self) -> Result<&str, Error> {
-        let splitted: Vec<&str> = self.role_arn.split('/').collect();
+        RoleArn::parse(&self.role_arn).map(|arn| arn.name)
+    }
 
-        match splitted.len() {
-            0 | 1 => bail!("Not enough elements in {}", self.role_arn),
-            2 => Ok(splitted[1]),
-            _ => bail!("Too many elements in {}", self.role_arn),
+    pub fn partition(&self) -> Result<Partition, Error> {
+        RoleArn::parse(&self.role_arn)?.partition.parse()
+    }
+
+    /// The ID of the AWS account the role is in
+    pub fn account_id(&self) -> Result<&str, Error> {
+        RoleArn::parse(&self.role_arn).map(|arn| arn.account_id)
+    }
+
+    /// Whether the ARN of this role is `role_arn`, or matches it as a glob
+    pub fn matches_arn(&self, role_arn: &str) -> bool {
+        glob_matches(role_arn, &self.role_arn)
+    }
+
+    /// Whether `role` is the name of this role, its name with its path (such as `team/deployer`
+    /// or `/team/deployer`) or its ARN, or a glob matching one of those
+    pub fn matches(&self, role: &str) -> bool {
+        match RoleArn::parse(&self.role_arn) {
+            Ok(arn) => {
+                let role = role.trim_start_matches('/');
+
+                glob_matches(role, arn.name)
+                    || glob_matches(role, arn.path_name)
+                    || glob_matches(role, &self.role_arn)
+            }
+            Err(_) => false,
         }
     }
 }
 
+/// Whether `value` is `pattern`, or matches it as a glob
+fn glob_matches(pattern: &str, value: &str) -> bool {
+    match Pattern::new(pattern) {
+        Ok(pattern) => pattern.matches(value),
+        Err(_) => pattern == value,
+    }
+}
+
+/// Bring a requested session duration within what STS and the identity provider allow
+pub fn clamp_duration(requested: Option<i64>, session_duration: Option<i64>) -> Option<i64> {
+    let requested = requested?;
+    let limit = session_duration
+        .unwrap_or(MAX_DURATION_SECONDS)
+        .clamp(MIN_DURATION_SECONDS, MAX_DURATION_SECONDS);
+    let clamped = requested.clamp(MIN_DURATION_SECONDS, limit);
+
+    if clamped != requested {
+        warn!(
+            "Requested duration of {}s is not between {}s and {}s, using {}s",
+            requested, MIN_DURATION_SECONDS, limit, clamped
+        );
+    }
+
+    Some(clamped)
+}
+
+/// A shorter duration to retry with, when the role does not allow as long a session as requested
+fn shorter_duration(duration_seconds: Option<i64>) -> Option<i64> {
+    match duration_seconds {
+        Some(duration) if duration > DEFAULT_DURATION_SECONDS => {
+            Some((duration / 2).max(DEFAULT_DURATION_SECONDS))
+        }
+        _ => None,
+    }
+}
+
+/// Assume `role`, retrying with a shorter duration if `shorten` is set and the role does not
+/// allow the one requested
 pub async fn assume_role(
-    Role {
-        provider_arn,
-        role_arn,
-    }: Role,
+    role: Role,
     saml_assertion: String,
     duration_seconds: Option<i64>,
+    shorten: bool,
 ) -> Result<AssumeRoleWithSAMLResponse, Error> {
-    let req = AssumeRoleWithSAMLRequest {
+    let region = role.partition()?.sts_region();
+
+    let mut req = AssumeRoleWithSAMLRequest {
         duration_seconds,
         policy: None,
-        principal_arn: provider_arn,
-        role_arn,
+        principal_arn: role.provider_arn,
+        role_arn: role.role_arn,
         saml_assertion,
         policy_arns: None,
     };
 
     let provider = StaticProvider::new_minimal(String::from(""), String::from(""));
-    let client = StsClient::new_with(HttpClient::new()?, provider, Region::default());
+    debug!("Calling STS in {}", region.name());
+    let client = StsClient::new_with(HttpClient::new()?, provider, region);
 
     trace!("Assuming role: {:?}", &req);
 
-    client
-        .assume_role_with_saml(req)
-        .await
-        .map_err(|e| e.into())
+    let mut attempt = 0;
+    // Durations the role did not allow, to report if none of them work
+    let mut durations_tried = Vec::new();
+
+    loop {
+        match client.assume_role_with_saml(req.clone()).await {
+            Err(RusotoError::Unknown(ref response))
+                if attempt < MAX_RETRIES && is_throttling(response.body_as_str()) =>
+            {
+                let delay = backoff(attempt);
+                warn!("Throttled by STS, retrying in {}s", delay.as_secs_f32());
+
+                sleep(delay).await;
+                attempt += 1;
+            }
+            Err(RusotoError::Unknown(ref response))
+                if shorten && is_duration_too_long(response.body_as_str()) =>
+            {
+                durations_tried.extend(req.duration_seconds);
+
+                match shorter_duration(req.duration_seconds) {
+                    Some(duration) => {
+                        warn!(
+                            "{} does not allow sessions of {}s, retrying with {}s",
+                            req.role_arn,
+                            req.duration_seconds.unwrap_or_default(),
+                            duration
+                        );
+                        req.duration_seconds = Some(duration);
+                    }
+                    None => {
+                        return Err(Error::Sts(format!(
+                            "{} does not allow sessions of {} ({})",
+                            req.role_arn,
+                            durations_tried
+                                .iter()
+                                .map(|duration| format!("{}s", duration))
+                                .collect::<Vec<_>>()
+                                .join(", "),
+                            error_message(response.body_as_str())
+                        )))
+                    }
+                }
+            }
+            result => return result.map_err(Into::into),
+        }
+    }
+}
+
+/// The message of the STS error response `body`, or the whole body if it has none
+fn error_message(body: &str) -> String {
+    roxmltree::Document::parse(body)
+        .ok()
+        .and_then(|document| {
+            document
+                .descendants()
+                .find(|node| node.has_tag_name("Message"))
+                .and_then(|node| node.text())
+                .map(str::to_string)
+        })
+        .unwrap_or_else(|| body.to_string())
+}
+
+/// Whether the STS error response `body` says we are being throttled
+fn is_throttling(body: &str) -> bool {
+    body.contains("<Code>Throttling</Code>")
+}
+
+/// Whether the STS error response `body` says the duration exceeds the role's `MaxSessionDuration`
+fn is_duration_too_long(body: &str) -> bool {
+    body.contains("<Code>ValidationError</Code>") && body.contains("MaxSessionDuration")
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::aws::role::Role;
+    use crate::aws::role::{
+        clamp_duration, error_message, is_duration_too_long, is_throttling, shorter_duration,
+        Partition, Role,
+    };
     use crate::saml::Response;
 
     use std::collections::HashSet;
     use std::convert::TryFrom;
-    use std::fs::File;
+    use std::fs::{read_to_string, File};
     use std::io::Read;
 
     use base64::encode;
@@ -95,6 +322,88 @@ mod tests {
         assert_eq!(attribute.parse::<Role>().unwrap(), expected_role);
     }
 
+    fn role(role_arn: &str) -> Role {
+        Role {
+            provider_arn: String::from("arn:aws:iam::123456789012:saml-provider/okta-idp"),
+            role_arn: String::from(role_arn),
+        }
+    }
+
+    #[test]
+    fn role_with_path() {
+        let role = role("arn:aws:iam::123456789012:role/team/deployer");
+
+        assert_eq!(role.role_name().unwrap(), "deployer");
+        assert!(role.matches("deployer"));
+        assert!(role.matches("team/deployer"));
+        assert!(role.matches("/team/deployer"));
+        assert!(!role.matches("team"));
+        assert!(!role.matches("other/deployer"));
+    }
+
+    #[test]
+    fn match_role_globs_and_arns() {
+        let role = role("arn:aws:iam::123456789012:role/team/deployer");
+
+        assert!(role.matches("deploy*"));
+        assert!(role.matches("team/*"));
+        assert!(role.matches("arn:aws:iam::123456789012:role/team/deployer"));
+        assert!(!role.matches("admin*"));
+
+        assert!(role.matches_arn("arn:aws:iam::123456789012:role/team/deployer"));
+        assert!(role.matches_arn("arn:aws:iam::123456789012:role/*"));
+        assert!(!role.matches_arn("arn:aws:iam::210987654321:role/*"));
+        assert!(!role.matches_arn("deployer"));
+
+        assert_eq!(role.account_id().unwrap(), "123456789012");
+    }
+
+    #[test]
+    fn role_partitions() {
+        assert_eq!(
+            role("arn:aws:iam::123456789012:role/role1")
+                .partition()
+                .unwrap(),
+            Partition::Aws
+        );
+        assert_eq!(
+            role("arn:aws-us-gov:iam::123456789012:role/role1")
+                .partition()
+                .unwrap(),
+            Partition::AwsUsGov
+        );
+        assert_eq!(
+            role("arn:aws-cn:iam::123456789012:role/role1")
+                .partition()
+                .unwrap(),
+            Partition::AwsCn
+        );
+        assert!(role("arn:aws-mars:iam::123456789012:role/role1")
+            .partition()
+            .is_err());
+    }
+
+    #[test]
+    fn invalid_role_arns() {
+        for role_arn in &[
+            "role1",
+            "arn:aws:iam::123456789012:user/role1",
+            "arn:aws:iam::123456789012:role/",
+            "arn:aws:iam::123456789012:role/team/",
+            "arn:aws:s3:::bucket/role/role1",
+        ] {
+            assert!(role(role_arn).role_name().is_err(), "{}", role_arn);
+            assert!(!role(role_arn).matches("role1"), "{}", role_arn);
+        }
+    }
+
+    #[test]
+    fn sts_regions() {
+        for partition in &[Partition::Aws, Partition::AwsUsGov, Partition::AwsCn] {
+            assert_eq!(Partition::of(&partition.sts_region()), *partition);
+        }
+    }
+
     #[test]
     fn parse_response() {
         let mut f = File::open("tests/fixtures/saml_response.xml").expect("file not found");
@@ -121,5 +430,44 @@ mod tests {
         .collect::<HashSet<Role>>();
 
         assert_eq!(response.roles, expected_roles);
+        assert_eq!(response.session_duration, Some(43200));
+    }
+
+    #[test]
+    fn clamp_durations() {
+        assert_eq!(clamp_duration(None, Some(7200)), None);
+        assert_eq!(clamp_duration(Some(3600), Some(7200)), Some(3600));
+        assert_eq!(clamp_duration(Some(28800), Some(7200)), Some(7200));
+        assert_eq!(clamp_duration(Some(86400), None), Some(43200));
+        assert_eq!(clamp_duration(Some(60), None), Some(900));
+    }
+
+    #[test]
+    fn shorter_durations() {
+        assert_eq!(shorter_duration(Some(43200)), Some(21600));
+        assert_eq!(shorter_duration(Some(5400)), Some(3600));
+        assert_eq!(shorter_duration(Some(3600)), None);
+        assert_eq!(shorter_duration(None), None);
+    }
+
+    #[test]
+    fn throttling_errors() {
+        let body = read_to_string("tests/fixtures/sts_throttling.xml").unwrap();
+
+        assert!(is_throttling(&body));
+        assert!(!is_duration_too_long(&body));
+    }
+
+    #[test]
+    fn max_session_duration_errors() {
+        let body = read_to_string("tests/fixtures/sts_max_session_duration.xml").unwrap();
+
+        assert!(is_duration_too_long(&body));
+        assert!(!is_throttling(&body));
+        assert_eq!(
+            error_message(&body),
+            "The requested DurationSeconds exceeds the MaxSessionDuration set for this role."
+        );
+        assert_eq!(error_message("Service Unavailable"), "Service Unavailable");
     }
 }
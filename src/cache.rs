@@ -0,0 +1,46 @@
+use crate::error::Error;
+
+use std::fs::{create_dir_all, File, OpenOptions};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// The directory oktaws keeps files in between runs
+pub fn dir() -> Result<PathBuf, Error> {
+    match dirs::cache_dir() {
+        Some(cache_dir) => Ok(cache_dir.join("oktaws")),
+        None => Err(Error::Config(
+            "Could not determine a cache directory".to_string(),
+        )),
+    }
+}
+
+/// Read a cached JSON file, if it exists and can be parsed
+pub fn read<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let file = File::open(path).ok()?;
+
+    match serde_json::from_reader(file) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            warn!("Ignoring unreadable cache file {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Write a JSON file that only the user can read, as it holds secrets
+pub fn write<T: Serialize>(path: &Path, value: &T) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    serde_json::to_writer(options.open(path)?, value).map_err(Into::into)
+}
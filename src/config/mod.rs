@@ -2,13 +2,15 @@ pub mod organization;
 pub mod profile;
 
 use crate::config::organization::Organization;
+use crate::config::profile::Profile;
+use crate::error::Error;
 
 use std::path::Path;
 use std::path::PathBuf;
 use std::{convert::TryInto, env::var as env_var};
 
-use failure::Error;
 use glob::Pattern;
+use itertools::Itertools;
 use walkdir::WalkDir;
 
 #[derive(Debug)]
@@ -18,13 +20,8 @@ pub struct Config {
 
 impl Config {
     pub fn new() -> Result<Config, Error> {
-        let oktaws_home = match env_var("OKTAWS_HOME") {
-            Ok(path) => PathBuf::from(path),
-            Err(_) => default_profile_location()?,
-        };
-
         Ok(Config {
-            organizations: organizations_from_dir(&oktaws_home).collect(),
+            organizations: organizations_from_dir(&oktaws_home()?).collect(),
         })
     }
 
@@ -33,6 +30,35 @@ impl Config {
             .into_iter()
             .filter(move |o| filter.matches(&o.name))
     }
+
+    /// Find the one profile called `name` among the organizations matching `filter`
+    pub fn into_profile(
+        self,
+        filter: Pattern,
+        name: &str,
+    ) -> Result<(Organization, Profile), Error> {
+        let mut matches = self
+            .into_organizations(filter)
+            .filter_map(|organization| {
+                let profile = organization
+                    .profiles
+                    .iter()
+                    .find(|p| p.name == name)?
+                    .clone();
+                Some((organization, profile))
+            })
+            .collect::<Vec<_>>();
+
+        match matches.len() {
+            0 => Err(Error::Config(format!("No profile found called {}", name))),
+            1 => Ok(matches.remove(0)),
+            _ => Err(Error::Config(format!(
+                "Profile {} found in multiple organizations ({}), use --organizations to choose one",
+                name,
+                matches.iter().map(|(o, _)| &o.name).join(", ")
+            ))),
+        }
+    }
 }
 
 fn organizations_from_dir(dir: &Path) -> impl Iterator<Item = Organization> {
@@ -55,10 +81,20 @@ fn organizations_from_dir(dir: &Path) -> impl Iterator<Item = Organization> {
         })
 }
 
+/// The directory organization files are read from
+pub fn oktaws_home() -> Result<PathBuf, Error> {
+    match env_var("OKTAWS_HOME") {
+        Ok(path) => Ok(PathBuf::from(path)),
+        Err(_) => default_profile_location(),
+    }
+}
+
 fn default_profile_location() -> Result<PathBuf, Error> {
     match dirs::home_dir() {
         Some(home_dir) => Ok(home_dir.join(".oktaws")),
-        None => bail!("The environment variable HOME must be set."),
+        None => Err(Error::Config(
+            "The environment variable HOME must be set.".to_string(),
+        )),
     }
 }
 
@@ -77,7 +113,12 @@ mod tests {
     fn create_mock_toml(dir: &Path, name: &str) {
         let filepath = dir.join(format!("{}.toml", name));
         let mut file = File::create(filepath).unwrap();
-        write!(file, "username = \"{}_user\"\n[profiles]", name).unwrap();
+        write!(
+            file,
+            "username = \"{}_user\"\nrole = \"my_role\"\n[profiles]\nshared = \"app\"\n{}_profile = \"app\"",
+            name, name
+        )
+        .unwrap();
     }
 
     fn create_mock_config_dir() -> TempDir {
@@ -149,4 +190,39 @@ mod tests {
             vec!["bar", "baz"]
         );
     }
+
+    #[test]
+    #[serial]
+    fn finds_single_profile() {
+        let tempdir = create_mock_config_dir();
+        env::set_var("OKTAWS_HOME", tempdir.path());
+
+        let config = Config::new().unwrap();
+        let (organization, profile) = config
+            .into_profile(Pattern::new("*").unwrap(), "foo_profile")
+            .unwrap();
+        assert_eq!(organization.name, "foo");
+        assert_eq!(profile.name, "foo_profile");
+
+        let config = Config::new().unwrap();
+        let err = config
+            .into_profile(Pattern::new("*").unwrap(), "missing")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "No profile found called missing");
+
+        let config = Config::new().unwrap();
+        let err = config
+            .into_profile(Pattern::new("*").unwrap(), "shared")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Profile shared found in multiple organizations (bar, baz, foo), use --organizations to choose one"
+        );
+
+        let config = Config::new().unwrap();
+        let (organization, _) = config
+            .into_profile(Pattern::new("ba[z]").unwrap(), "shared")
+            .unwrap();
+        assert_eq!(organization.name, "baz");
+    }
 }
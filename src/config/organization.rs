@@ -1,32 +1,235 @@
-use crate::config::profile::{Profile, ProfileConfig};
+use crate::config::profile::{FullProfileConfig, Profile, ProfileConfig};
+use crate::error::Error;
 use crate::okta::client::Client as OktaClient;
+use crate::okta::factors::FactorPreference;
+use crate::saml::signature::SamlCertificate;
+use crate::totp::TotpSecret;
+use crate::tty;
 
 use std::convert::TryFrom;
 use std::fmt::Display;
-use std::fs::read_to_string;
-use std::path::Path;
+use std::fs::{read_to_string, write};
+use std::path::{Path, PathBuf};
 
-use dialoguer::Input;
-use failure::Error;
-use futures::future::join_all;
+use chrono::Duration;
+use dialoguer::{FuzzySelect, Input, MultiSelect};
+use futures::stream::{self, StreamExt};
 use glob::Pattern;
 use indexmap::IndexMap;
 use rusoto_sts::Credentials;
 use serde::{Deserialize, Serialize};
 use toml;
+use toml_edit::{value, Document, InlineTable};
+use url::Url;
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct OrganizationConfig {
+    pub base_url: Option<Url>,
     pub role: Option<String>,
     pub username: Option<String>,
     pub duration_seconds: Option<i64>,
+    pub region: Option<String>,
+    pub refresh_window_seconds: Option<i64>,
+    pub clamp_duration: Option<bool>,
+    pub factor: Option<String>,
+    pub totp_secret: Option<String>,
+    pub saml_private_key: Option<PathBuf>,
+    pub saml_certificate: Option<String>,
+    // Profiles written as tables must follow those written as plain application names
+    #[serde(serialize_with = "toml::ser::tables_last")]
     pub profiles: IndexMap<String, ProfileConfig>,
 }
 
+impl OrganizationConfig {
+    /// Build profiles for the AWS applications assigned to the user in Okta, asking them which
+    /// applications to include, what to call each profile and which roles to use
+    pub async fn from_client(client: &OktaClient) -> Result<OrganizationConfig, Error> {
+        let app_links = client
+            .app_links(None)
+            .await?
+            .into_iter()
+            .filter(|app_link| app_link.app_name == "amazon_aws")
+            .collect::<Vec<_>>();
+
+        if app_links.is_empty() {
+            return Err(Error::Config(
+                "No AWS applications are assigned to you in Okta".to_string(),
+            ));
+        }
+
+        let labels = app_links
+            .iter()
+            .map(|app_link| &app_link.label)
+            .collect::<Vec<_>>();
+
+        let selections = MultiSelect::new()
+            .with_prompt("Choose the AWS applications to create profiles for")
+            .items(&labels)
+            .defaults(&vec![true; labels.len()])
+            .interact()?;
+
+        // The profile name, application and available roles (as their names and account IDs) of
+        // each selected application
+        let mut applications = Vec::new();
+        for selection in selections {
+            let app_link = &app_links[selection];
+
+            let saml = client.get_saml_response(app_link.link_url.clone()).await?;
+
+            let mut roles = saml
+                .roles
+                .iter()
+                .map(|role| {
+                    Ok((
+                        role.role_name()?.to_string(),
+                        role.account_id()?.to_string(),
+                    ))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            roles.sort();
+
+            let name = loop {
+                let name = Input::<String>::new()
+                    .with_prompt(format!("Profile name for {}", app_link.label))
+                    .default(default_profile_name(&app_link.label))
+                    .interact_text()?;
+
+                if applications
+                    .iter()
+                    .any(|(existing, _, _): &(String, _, _)| *existing == name)
+                {
+                    warn!("There is already a profile called {}", name);
+                } else {
+                    break name;
+                }
+            };
+
+            applications.push((name, app_link.label.clone(), roles));
+        }
+
+        // Offer the roles available in the most applications first
+        let mut role_counts = IndexMap::<&str, usize>::new();
+        for (_, _, roles) in &applications {
+            let mut role_names = roles
+                .iter()
+                .map(|(role_name, _)| role_name.as_str())
+                .collect::<Vec<_>>();
+            role_names.dedup();
+
+            for role_name in role_names {
+                *role_counts.entry(role_name).or_default() += 1;
+            }
+        }
+        role_counts.sort_by(|a, a_count, b, b_count| b_count.cmp(a_count).then(a.cmp(b)));
+
+        let role = match role_counts.len() {
+            0 => None,
+            1 => role_counts.keys().next().map(|role| role.to_string()),
+            _ => {
+                let items = role_counts
+                    .iter()
+                    .map(|(role, count)| {
+                        format!("{} ({} of {} profiles)", role, count, applications.len())
+                    })
+                    .collect::<Vec<_>>();
+
+                let selection = FuzzySelect::new()
+                    .with_prompt("Choose a default role")
+                    .items(&items)
+                    .default(0)
+                    .interact()?;
+
+                role_counts
+                    .get_index(selection)
+                    .map(|(role, _)| role.to_string())
+            }
+        };
+
+        let mut profiles = IndexMap::new();
+        for (name, application, roles) in applications {
+            // A role name used in several accounts also needs the account to identify the role
+            let is_ambiguous = |role_name: &str| {
+                roles
+                    .iter()
+                    .filter(|(other_name, _)| other_name == role_name)
+                    .count()
+                    > 1
+            };
+
+            let has_default_role = role
+                .as_deref()
+                .map(|role| {
+                    roles.iter().any(|(role_name, _)| role_name == role) && !is_ambiguous(role)
+                })
+                .unwrap_or(false);
+
+            let profile_config = if has_default_role || roles.is_empty() {
+                ProfileConfig::Name(application)
+            } else {
+                let selection = match roles.len() {
+                    1 => 0,
+                    _ => FuzzySelect::new()
+                        .with_prompt(format!("Choose a role for {}", name))
+                        .items(
+                            &roles
+                                .iter()
+                                .map(|(role_name, account_id)| {
+                                    if is_ambiguous(role_name) {
+                                        format!("{} ({})", role_name, account_id)
+                                    } else {
+                                        role_name.clone()
+                                    }
+                                })
+                                .collect::<Vec<_>>(),
+                        )
+                        .default(0)
+                        .interact()?,
+                };
+
+                let (role_name, account_id) = &roles[selection];
+
+                ProfileConfig::Detailed(FullProfileConfig {
+                    application,
+                    role: Some(role_name.clone()),
+                    account_id: if is_ambiguous(role_name) {
+                        Some(account_id.clone())
+                    } else {
+                        None
+                    },
+                    role_arn: None,
+                    duration_seconds: None,
+                    region: None,
+                })
+            };
+
+            profiles.insert(name, profile_config);
+        }
+
+        Ok(OrganizationConfig {
+            role,
+            profiles,
+            ..OrganizationConfig::default()
+        })
+    }
+}
+
+/// Credentials expiring within this many seconds are refreshed, unless configured otherwise
+const DEFAULT_REFRESH_WINDOW_SECONDS: i64 = 300;
+
 #[derive(Clone, Debug)]
 pub struct Organization {
     pub name: String,
+    pub base_url: Url,
     pub username: String,
+    pub refresh_window: Duration,
+    /// The MFA factor to use without asking
+    pub factor: Option<FactorPreference>,
+    /// Where to read the secret for generating TOTP passcodes from, instead of asking for them
+    pub totp_secret: Option<TotpSecret>,
+    /// The private key to decrypt encrypted SAML assertions with
+    pub saml_private_key: Option<PathBuf>,
+    /// Where to get the certificate to verify SAML responses with
+    pub saml_certificate: Option<SamlCertificate>,
     pub profiles: Vec<Profile>,
 }
 
@@ -39,7 +242,11 @@ impl TryFrom<&Path> for Organization {
         let filename = path
             .file_stem()
             .map(|stem| stem.to_string_lossy().into_owned())
-            .ok_or_else(|| format_err!("Organization name not parseable from {:?}", path))?;
+            .ok_or_else(|| {
+                Error::Config(format!("Organization name not parseable from {:?}", path))
+            })?;
+
+        let base_url = base_url(cfg.base_url.clone(), &filename)?;
 
         let username = match cfg.clone().username {
             Some(username) => username,
@@ -53,15 +260,46 @@ impl TryFrom<&Path> for Organization {
                 Profile::try_from_config(
                     profile_config,
                     name.to_string(),
+                    path,
                     cfg.role.clone(),
                     cfg.duration_seconds,
+                    cfg.region.clone(),
+                    cfg.clamp_duration.unwrap_or(true),
                 )
             })
             .collect::<Result<Vec<Profile>, Error>>()?;
 
+        let totp_secret = cfg
+            .totp_secret
+            .as_deref()
+            .map(|totp_secret| {
+                TotpSecret::from_config(totp_secret, path.parent(), &filename, &username)
+            })
+            .transpose()?;
+
+        // Relative to the organization file, so it can be kept alongside it
+        let saml_private_key = cfg.saml_private_key.map(|key| match path.parent() {
+            Some(dir) => dir.join(key),
+            None => key,
+        });
+        let saml_certificate = cfg
+            .saml_certificate
+            .as_deref()
+            .map(|certificate| SamlCertificate::from_config(certificate, path.parent()))
+            .transpose()?;
+
         Ok(Organization {
             name: filename,
+            base_url,
             username,
+            refresh_window: Duration::seconds(
+                cfg.refresh_window_seconds
+                    .unwrap_or(DEFAULT_REFRESH_WINDOW_SECONDS),
+            ),
+            factor: cfg.factor.as_deref().map(str::parse).transpose()?,
+            totp_secret,
+            saml_private_key,
+            saml_certificate,
             profiles,
         })
     }
@@ -78,24 +316,104 @@ impl Organization {
         self,
         client: &OktaClient,
         filter: Pattern,
-    ) -> impl Iterator<Item = (String, Credentials)> {
+        max_concurrency: usize,
+    ) -> impl Iterator<Item = (String, Result<Credentials, Error>)> {
         let org_name = self.name.clone();
 
-        let futures = self.into_profiles(filter).map(|profile| async {
+        // Ask for any roles that are not configured first, as prompts for profiles fetched at the
+        // same time would interleave
+        let mut profiles = Vec::new();
+        let mut failures = Vec::new();
+        for mut profile in self.into_profiles(filter) {
+            match profile.resolve_role(client).await {
+                Ok(()) => profiles.push(profile),
+                Err(e) => failures.push((profile.name, Err(e))),
+            }
+        }
+
+        let futures = profiles.into_iter().map(|profile| async {
             let name = profile.name.clone();
 
             info!("Requesting tokens for {}/{}", org_name, profile.name);
 
-            let credentials = profile.into_credentials(&client).await.unwrap();
+            let credentials = profile.into_credentials(&client).await;
 
             (name, credentials)
         });
 
-        join_all(futures).await.into_iter()
+        stream::iter(futures)
+            .buffered(max_concurrency.max(1))
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .chain(failures)
+    }
+}
+
+/// The Okta URL of an organization, defaulting to `https://<organization>.okta.com/`
+pub fn base_url(base_url: Option<Url>, organization: &str) -> Result<Url, Error> {
+    match base_url {
+        Some(mut base_url) => {
+            // Without a trailing slash, joining API paths would replace the last segment
+            if !base_url.path().ends_with('/') {
+                base_url.set_path(&format!("{}/", base_url.path()));
+            }
+            Ok(base_url)
+        }
+        None => Url::parse(&format!("https://{}.okta.com/", organization)).map_err(Into::into),
+    }
+}
+
+/// A profile name for an Okta application, such as `my-account` for `My Account`
+fn default_profile_name(label: &str) -> String {
+    label
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Set the role of a profile in an organization file, by name or ARN as `key` is `role` or
+/// `role_arn`, leaving the rest of the file untouched
+pub fn save_profile_role(path: &Path, profile: &str, key: &str, role: &str) -> Result<(), Error> {
+    let mut document = read_to_string(path)?
+        .parse::<Document>()
+        .map_err(|e| Error::Config(e.to_string()))?;
+
+    let not_found = || Error::Config(format!("Profile {} not found in {:?}", profile, path));
+
+    let item = document
+        .as_table_mut()
+        .get_mut("profiles")
+        .and_then(|profiles| profiles.as_table_mut())
+        .and_then(|profiles| profiles.get_mut(profile))
+        .ok_or_else(not_found)?;
+
+    if let Some(application) = item.as_str().map(str::to_string) {
+        // `profile = "application"` becomes `profile = { application = "application", role = "role" }`
+        let mut table = InlineTable::default();
+        table.get_or_insert("application", application.as_str());
+        table.get_or_insert(key, role);
+        InlineTable::fmt(&mut table);
+        *item = value(table);
+    } else if let Some(table) = item.as_inline_table_mut() {
+        table.remove(key);
+        table.get_or_insert(key, role);
+        table.fmt();
+    } else if let Some(table) = item.as_table_mut() {
+        table[key] = value(role);
+    } else {
+        return Err(not_found());
     }
+
+    info!("Saving role {} for profile {} to {:?}", role, profile, path);
+    write(path, document.to_string()).map_err(Into::into)
 }
 
 pub fn prompt_username(organization: &impl Display) -> Result<String, Error> {
+    tty::require("the Okta username")?;
+
     let mut input = Input::<String>::new();
     input.with_prompt(&format!("Username for {}", organization));
 
@@ -127,11 +445,17 @@ mod tests {
             r#"
 username = "mock_user"
 duration_seconds = 300
+refresh_window_seconds = 60
+factor = "totp:google"
+totp_secret = "env:MOCK_TOTP_SECRET"
+saml_private_key = "keys/mock_org.pem"
+saml_certificate = "keys/okta.cert"
 role = "my_role"
+region = "us-east-1"
 [profiles]
 foo = "foo"
 bar = {{ application = "bar", duration_seconds = 600 }}
-baz = {{ application = "baz", role = "baz_role" }}
+baz = {{ application = "baz", role = "baz_role", region = "eu-west-1" }}
 "#
         )
         .unwrap();
@@ -139,23 +463,80 @@ baz = {{ application = "baz", role = "baz_role" }}
         let organization = Organization::try_from(filepath.as_path()).unwrap();
 
         assert_eq!(organization.name, "mock_org");
+        assert_eq!(organization.base_url.as_str(), "https://mock_org.okta.com/");
         assert_eq!(organization.username, "mock_user");
+        assert_eq!(organization.refresh_window, Duration::seconds(60));
+        assert_eq!(
+            organization
+                .factor
+                .map(|factor| factor.to_string())
+                .as_deref(),
+            Some("totp:google")
+        );
+        assert_eq!(
+            organization.totp_secret,
+            Some(TotpSecret::Env("MOCK_TOTP_SECRET".to_string()))
+        );
+        assert_eq!(
+            organization.saml_private_key,
+            Some(tempdir.path().join("keys/mock_org.pem"))
+        );
+        assert_eq!(
+            organization.saml_certificate,
+            Some(SamlCertificate::File(tempdir.path().join("keys/okta.cert")))
+        );
         assert_eq!(organization.profiles.len(), 3);
 
         assert_eq!(organization.profiles[0].name, "foo");
         assert_eq!(organization.profiles[0].application_name, "foo");
-        assert_eq!(organization.profiles[0].role, "my_role");
+        assert_eq!(organization.profiles[0].role.as_deref(), Some("my_role"));
         assert_eq!(organization.profiles[0].duration_seconds, Some(300));
+        assert_eq!(
+            organization.profiles[0].region.as_deref(),
+            Some("us-east-1")
+        );
 
         assert_eq!(organization.profiles[1].name, "bar");
         assert_eq!(organization.profiles[1].application_name, "bar");
-        assert_eq!(organization.profiles[1].role, "my_role");
+        assert_eq!(organization.profiles[1].role.as_deref(), Some("my_role"));
         assert_eq!(organization.profiles[1].duration_seconds, Some(600));
 
         assert_eq!(organization.profiles[2].name, "baz");
         assert_eq!(organization.profiles[2].application_name, "baz");
-        assert_eq!(organization.profiles[2].role, "baz_role");
+        assert_eq!(organization.profiles[2].role.as_deref(), Some("baz_role"));
         assert_eq!(organization.profiles[2].duration_seconds, Some(300));
+        assert_eq!(
+            organization.profiles[2].region.as_deref(),
+            Some("eu-west-1")
+        );
+    }
+
+    #[test]
+    fn parse_organization_with_base_url() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let filepath = tempdir.path().join("mock_org.toml");
+        let mut file = File::create(filepath.clone()).unwrap();
+
+        write!(
+            file,
+            r#"
+username = "mock_user"
+base_url = "https://login.example.com/okta"
+role = "my_role"
+[profiles]
+foo = "foo"
+"#
+        )
+        .unwrap();
+
+        let organization = Organization::try_from(filepath.as_path()).unwrap();
+
+        assert_eq!(organization.name, "mock_org");
+        assert_eq!(
+            organization.base_url.as_str(),
+            "https://login.example.com/okta/"
+        );
     }
 
     #[test]
@@ -182,7 +563,7 @@ username = "mock_user"
     }
 
     #[test]
-    fn profile_must_have_role() {
+    fn profile_without_role() {
         let tempdir = tempfile::tempdir().unwrap();
 
         let filepath = tempdir.path().join("mock_org.toml");
@@ -198,9 +579,111 @@ foo = "foo"
         )
         .unwrap();
 
-        let err = Organization::try_from(filepath.as_path()).unwrap_err();
+        let organization = Organization::try_from(filepath.as_path()).unwrap();
+
+        assert_eq!(organization.profiles[0].role, None);
+    }
+
+    #[test]
+    fn save_role() {
+        let tempdir = tempfile::tempdir().unwrap();
+
+        let filepath = tempdir.path().join("mock_org.toml");
+        let mut file = File::create(filepath.clone()).unwrap();
+
+        write!(
+            file,
+            r#"# My organization
+username = "mock_user"
+
+[profiles]
+# An important profile
+foo = "foo"
+bar = {{ application = "bar", role = "old_role" }}
+qux = "qux"
+
+[profiles.baz]
+application = "baz"
+"#
+        )
+        .unwrap();
+
+        for profile in ["foo", "bar", "baz"] {
+            save_profile_role(&filepath, profile, "role", &format!("{}_role", profile)).unwrap();
+        }
+        save_profile_role(
+            &filepath,
+            "qux",
+            "role_arn",
+            "arn:aws:iam::123456789012:role/qux_role",
+        )
+        .unwrap();
+
+        let organization = Organization::try_from(filepath.as_path()).unwrap();
+
+        assert_eq!(organization.profiles[0].application_name, "foo");
+        assert_eq!(organization.profiles[0].role.as_deref(), Some("foo_role"));
+        assert_eq!(organization.profiles[1].application_name, "bar");
+        assert_eq!(organization.profiles[1].role.as_deref(), Some("bar_role"));
+        assert_eq!(organization.profiles[2].application_name, "qux");
+        assert_eq!(organization.profiles[2].role, None);
+        assert_eq!(
+            organization.profiles[2].role_arn.as_deref(),
+            Some("arn:aws:iam::123456789012:role/qux_role")
+        );
+        assert_eq!(organization.profiles[3].application_name, "baz");
+        assert_eq!(organization.profiles[3].role.as_deref(), Some("baz_role"));
 
-        assert_eq!(err.to_string(), "No role found");
+        let contents = read_to_string(&filepath).unwrap();
+        assert!(contents.starts_with("# My organization\n"));
+        assert!(contents.contains("# An important profile"));
+
+        let err = save_profile_role(&filepath, "missing", "role", "role").unwrap_err();
+        assert!(matches!(err, Error::Config(_)));
+    }
+
+    #[test]
+    fn serialize_organization() {
+        let mut profiles = IndexMap::new();
+        profiles.insert(
+            "bar".to_string(),
+            ProfileConfig::Detailed(FullProfileConfig {
+                application: "Bar".to_string(),
+                role: Some("bar_role".to_string()),
+                account_id: None,
+                role_arn: None,
+                duration_seconds: None,
+                region: None,
+            }),
+        );
+        profiles.insert("foo".to_string(), ProfileConfig::Name("Foo".to_string()));
+
+        let cfg = OrganizationConfig {
+            username: Some("mock_user".to_string()),
+            role: Some("my_role".to_string()),
+            profiles,
+            ..OrganizationConfig::default()
+        };
+
+        assert_eq!(
+            toml::to_string(&cfg).unwrap(),
+            r#"role = "my_role"
+username = "mock_user"
+
+[profiles]
+foo = "Foo"
+
+[profiles.bar]
+application = "Bar"
+role = "bar_role"
+"#
+        );
+    }
+
+    #[test]
+    fn profile_name_from_label() {
+        assert_eq!(default_profile_name("My Account"), "my-account");
+        assert_eq!(default_profile_name("AWS (Production)"), "aws-production");
     }
 
     #[test]
@@ -223,11 +706,16 @@ foo = "foo"
 
         let organization = Organization::try_from(filepath.as_path()).unwrap();
 
+        assert_eq!(
+            organization.refresh_window,
+            Duration::seconds(DEFAULT_REFRESH_WINDOW_SECONDS)
+        );
         assert_eq!(organization.profiles.len(), 1);
 
         assert_eq!(organization.profiles[0].name, "foo");
         assert_eq!(organization.profiles[0].application_name, "foo");
-        assert_eq!(organization.profiles[0].role, "my_role");
+        assert_eq!(organization.profiles[0].role.as_deref(), Some("my_role"));
         assert_eq!(organization.profiles[0].duration_seconds, None);
+        assert_eq!(organization.profiles[0].region, None);
     }
 }
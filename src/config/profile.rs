@@ -1,6 +1,17 @@
-use crate::{aws::role::Role, okta::client::Client as OktaClient};
+use crate::config::organization::save_profile_role;
+use crate::error::Error;
+use crate::saml::Response as SamlResponse;
+use crate::tty;
+use crate::{
+    aws::role::{clamp_duration, Role},
+    okta::client::Client as OktaClient,
+};
 
-use failure::{err_msg, Error};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use dialoguer::{Confirm, FuzzySelect};
 use rusoto_sts::Credentials;
 use serde::{Deserialize, Serialize};
 
@@ -15,7 +26,12 @@ pub enum ProfileConfig {
 pub struct FullProfileConfig {
     pub application: String,
     pub role: Option<String>,
+    /// The AWS account of the role, when the role name is used in several accounts
+    pub account_id: Option<String>,
+    /// The ARN of the role (or a glob matching it), instead of its name
+    pub role_arn: Option<String>,
     pub duration_seconds: Option<i64>,
+    pub region: Option<String>,
 }
 
 impl From<ProfileConfig> for FullProfileConfig {
@@ -25,7 +41,10 @@ impl From<ProfileConfig> for FullProfileConfig {
             ProfileConfig::Name(application) => FullProfileConfig {
                 application,
                 role: None,
+                account_id: None,
+                role_arn: None,
                 duration_seconds: None,
+                region: None,
             },
         }
     }
@@ -35,33 +54,118 @@ impl From<ProfileConfig> for FullProfileConfig {
 pub struct Profile {
     pub name: String,
     pub application_name: String,
-    pub role: String,
+    pub role: Option<String>,
+    pub account_id: Option<String>,
+    pub role_arn: Option<String>,
     pub duration_seconds: Option<i64>,
+    pub region: Option<String>,
+    /// Whether to shorten `duration_seconds` to what the identity provider and role allow,
+    /// instead of failing
+    pub clamp_duration: bool,
+    /// The organization file this profile is configured in
+    pub config_path: PathBuf,
 }
 
 impl Profile {
     pub fn try_from_config(
         profile_config: &ProfileConfig,
         name: String,
+        config_path: &Path,
         default_role: Option<String>,
         default_duration_seconds: Option<i64>,
+        default_region: Option<String>,
+        clamp_duration: bool,
     ) -> Result<Profile, Error> {
         let full_profile_config: FullProfileConfig = profile_config.to_owned().into();
 
         Ok(Profile {
             name,
             application_name: full_profile_config.application,
-            role: full_profile_config
-                .role
-                .or(default_role)
-                .ok_or_else(|| err_msg("No role found"))?,
+            // A role ARN chooses the role by itself, so the organization's role name doesn't apply
+            role: match full_profile_config.role_arn {
+                Some(_) => full_profile_config.role,
+                None => full_profile_config.role.or(default_role),
+            },
+            account_id: full_profile_config.account_id,
+            role_arn: full_profile_config.role_arn,
             duration_seconds: full_profile_config
                 .duration_seconds
                 .or(default_duration_seconds),
+            region: full_profile_config.region.or(default_region),
+            clamp_duration,
+            config_path: config_path.to_path_buf(),
         })
     }
 
     pub async fn into_credentials(self, client: &OktaClient) -> Result<Credentials, Error> {
+        let saml = self.saml_response(client).await?;
+
+        debug!("SAML Roles: {:?}", &saml.roles);
+
+        let role = if self.role.is_some() || self.role_arn.is_some() {
+            self.find_role(&saml.roles)?
+        } else {
+            self.select_role(&saml.roles)?
+        };
+
+        trace!("Found role: {} for profile {}", role.role_arn, &self.name);
+
+        let duration_seconds = if self.clamp_duration {
+            clamp_duration(self.duration_seconds, saml.session_duration)
+        } else {
+            self.duration_seconds
+        };
+
+        let assumption_response = crate::aws::role::assume_role(
+            role,
+            saml.raw.clone(),
+            duration_seconds,
+            self.clamp_duration,
+        )
+        .await
+        .map_err(|e| match e {
+            Error::Sts(e) => Error::Sts(format!(
+                "Error assuming role for profile {} ({})",
+                self.name, e
+            )),
+            e => e,
+        })?;
+
+        let credentials = assumption_response.credentials.ok_or_else(|| {
+            Error::Sts("Error fetching credentials from assumed AWS role".to_string())
+        })?;
+
+        trace!("Credentials: {:?}", credentials);
+
+        Ok(credentials)
+    }
+
+    /// Ask which role to use now if none is configured, so that it is not asked for while
+    /// credentials for other profiles are being fetched
+    pub async fn resolve_role(&mut self, client: &OktaClient) -> Result<(), Error> {
+        if self.role.is_none() && self.role_arn.is_none() {
+            let saml = self.saml_response(client).await?;
+            self.role_arn = Some(self.select_role(&saml.roles)?.role_arn);
+        }
+
+        Ok(())
+    }
+
+    /// The roles Okta offers for this profile's application, by name
+    pub async fn roles(&self, client: &OktaClient) -> Result<Vec<Role>, Error> {
+        let mut roles = self
+            .saml_response(client)
+            .await?
+            .roles
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        roles.sort_by(|a, b| a.role_name().ok().cmp(&b.role_name().ok()));
+
+        Ok(roles)
+    }
+
+    async fn saml_response(&self, client: &OktaClient) -> Result<Arc<SamlResponse>, Error> {
         let app_link = client
             .app_links(None)
             .await?
@@ -70,52 +174,222 @@ impl Profile {
                 app_link.app_name == "amazon_aws" && app_link.label == self.application_name
             })
             .ok_or_else(|| {
-                format_err!("Could not find Okta application for profile {}", self.name)
+                Error::Config(format!(
+                    "Could not find Okta application for profile {}",
+                    self.name
+                ))
             })?;
 
         debug!("Application Link: {:?}", &app_link);
 
-        let saml = client
+        client
             .get_saml_response(app_link.link_url)
             .await
-            .map_err(|e| {
-                format_err!(
+            .map_err(|e| match e {
+                Error::Saml(e) => Error::Saml(format!(
                     "Error getting SAML response for profile {} ({})",
-                    self.name,
-                    e
-                )
-            })?;
+                    self.name, e
+                )),
+                e => e,
+            })
+    }
 
-        let roles = saml.roles;
+    /// Whether `role` is in this profile's account, and matches its role name and ARN
+    pub fn matches_role(&self, role: &Role) -> bool {
+        let account_id = match self.account_id {
+            Some(ref account_id) => role.account_id().ok() == Some(account_id.as_str()),
+            None => true,
+        };
+        let role_arn = match self.role_arn {
+            Some(ref role_arn) => role.matches_arn(role_arn),
+            None => true,
+        };
+        let role_name = match self.role {
+            Some(ref role_name) => role.matches(role_name),
+            None => true,
+        };
 
-        debug!("SAML Roles: {:?}", &roles);
+        account_id && role_arn && role_name
+    }
 
-        let role: Role = roles
-            .into_iter()
-            .find(|r| r.role_name().map(|r| r == self.role).unwrap_or(false))
-            .ok_or_else(|| {
-                format_err!(
+    /// The one role matching this profile's configuration
+    fn find_role(&self, roles: &HashSet<Role>) -> Result<Role, Error> {
+        let mut matches = roles
+            .iter()
+            .filter(|role| self.matches_role(role))
+            .collect::<Vec<_>>();
+        matches.sort_by(|a, b| a.role_arn.cmp(&b.role_arn));
+
+        match matches[..] {
+            [role] => Ok(role.clone()),
+            [] => {
+                let criteria = [
+                    ("role", &self.role),
+                    ("account_id", &self.account_id),
+                    ("role_arn", &self.role_arn),
+                ]
+                .iter()
+                .filter_map(|(key, value)| {
+                    value.as_ref().map(|value| format!("{} = {}", key, value))
+                })
+                .collect::<Vec<_>>();
+
+                Err(Error::RoleNotFound(format!(
                     "No matching role ({}) found for profile {}",
-                    self.role,
+                    criteria.join(", "),
                     &self.name
-                )
-            })?;
+                )))
+            }
+            _ => Err(Error::Config(format!(
+                "Profile {} matches several roles ({}), set account_id or role_arn to choose one",
+                self.name,
+                matches
+                    .iter()
+                    .map(|role| role.role_arn.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))),
+        }
+    }
 
-        trace!("Found role: {} for profile {}", role.role_arn, &self.name);
+    /// Ask the user which role to use, as none is configured, and offer to save it
+    fn select_role(&self, roles: &HashSet<Role>) -> Result<Role, Error> {
+        let mut roles = roles
+            .iter()
+            .filter(|role| self.matches_role(role))
+            .collect::<Vec<_>>();
+        roles.sort_by(|a, b| a.role_arn.cmp(&b.role_arn));
 
-        let assumption_response =
-            crate::aws::role::assume_role(role, saml.raw, self.duration_seconds)
-                .await
-                .map_err(|e| {
-                    format_err!("Error assuming role for profile {} ({})", self.name, e)
-                })?;
+        if roles.is_empty() {
+            return Err(Error::RoleNotFound(format!(
+                "No roles available for profile {}",
+                self.name
+            )));
+        }
 
-        let credentials = assumption_response
-            .credentials
-            .ok_or_else(|| format_err!("Error fetching credentials from assumed AWS role"))?;
+        tty::require(&format!("a role for profile {}", self.name))?;
 
-        trace!("Credentials: {:?}", credentials);
+        let selection = FuzzySelect::new()
+            .with_prompt(format!("Choose a role for {}", self.name))
+            .items(&roles.iter().map(|r| &r.role_arn).collect::<Vec<_>>())
+            .default(0)
+            .interact()?;
 
-        Ok(credentials)
+        let role = roles[selection].clone();
+
+        // The name alone would be ambiguous if other accounts have a role with the same name
+        let role_name = role.role_name()?;
+        let (key, role_name) = if roles
+            .iter()
+            .filter(|r| r.role_name().ok() == Some(role_name))
+            .count()
+            > 1
+        {
+            ("role_arn", role.role_arn.as_str())
+        } else {
+            ("role", role_name)
+        };
+
+        let save = Confirm::new()
+            .with_prompt(format!(
+                "Save {} as the role for {} in {}?",
+                role_name,
+                self.name,
+                self.config_path.display()
+            ))
+            .default(true)
+            .interact()?;
+
+        if save {
+            save_profile_role(&self.config_path, &self.name, key, role_name)?;
+        }
+
+        Ok(role)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(role: Option<&str>, account_id: Option<&str>, role_arn: Option<&str>) -> Profile {
+        Profile {
+            name: String::from("foo"),
+            application_name: String::from("Foo"),
+            role: role.map(String::from),
+            account_id: account_id.map(String::from),
+            role_arn: role_arn.map(String::from),
+            duration_seconds: None,
+            region: None,
+            clamp_duration: true,
+            config_path: PathBuf::from("foo.toml"),
+        }
+    }
+
+    fn roles() -> HashSet<Role> {
+        [
+            "arn:aws:iam::111111111111:role/admin",
+            "arn:aws:iam::222222222222:role/admin",
+            "arn:aws:iam::222222222222:role/team/deployer",
+        ]
+        .iter()
+        .map(|role_arn| Role {
+            provider_arn: String::from("arn:aws:iam::123456789012:saml-provider/okta-idp"),
+            role_arn: String::from(*role_arn),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn find_roles() {
+        let roles = roles();
+        let find = |profile: Profile| profile.find_role(&roles).map(|role| role.role_arn);
+
+        assert_eq!(
+            find(profile(Some("deployer"), None, None)).unwrap(),
+            "arn:aws:iam::222222222222:role/team/deployer"
+        );
+        assert_eq!(
+            find(profile(Some("admin"), Some("222222222222"), None)).unwrap(),
+            "arn:aws:iam::222222222222:role/admin"
+        );
+        assert_eq!(
+            find(profile(
+                None,
+                None,
+                Some("arn:aws:iam::111111111111:role/*")
+            ))
+            .unwrap(),
+            "arn:aws:iam::111111111111:role/admin"
+        );
+        assert_eq!(
+            find(profile(Some("dep*"), None, None)).unwrap(),
+            "arn:aws:iam::222222222222:role/team/deployer"
+        );
+    }
+
+    #[test]
+    fn ambiguous_roles() {
+        let err = profile(Some("admin"), None, None)
+            .find_role(&roles())
+            .unwrap_err();
+
+        assert!(matches!(err, Error::Config(_)));
+        assert_eq!(
+            err.to_string(),
+            "Profile foo matches several roles (arn:aws:iam::111111111111:role/admin, arn:aws:iam::222222222222:role/admin), set account_id or role_arn to choose one"
+        );
+    }
+
+    #[test]
+    fn missing_roles() {
+        let err = profile(Some("admin"), Some("333333333333"), None)
+            .find_role(&roles())
+            .unwrap_err();
+
+        assert!(matches!(err, Error::RoleNotFound(_)));
+        assert!(err
+            .to_string()
+            .contains("role = admin, account_id = 333333333333"));
     }
 }
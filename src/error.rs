@@ -0,0 +1,171 @@
+use crate::okta::client::ClientError;
+
+use std::io;
+use std::string::FromUtf8Error;
+
+use rusoto_core::request::TlsError;
+use rusoto_core::RusotoError;
+use thiserror::Error as ThisError;
+
+#[derive(Debug, ThisError)]
+pub enum Error {
+    /// The oktaws or AWS configuration is missing or invalid
+    #[error("{0}")]
+    Config(String),
+    /// Okta did not accept the user's credentials
+    #[error("{0}")]
+    Authentication(String),
+    /// The MFA challenge was rejected, timed out or could not be completed
+    #[error("{0}")]
+    MfaRejected(String),
+    /// No usable SAML assertion was returned by Okta
+    #[error("{0}")]
+    Saml(String),
+    /// The configured role is not one the user can assume
+    #[error("{0}")]
+    RoleNotFound(String),
+    /// AWS STS refused to issue credentials
+    #[error("{0}")]
+    Sts(String),
+    /// Some profiles could not be refreshed, but the others were saved. The process exits with
+    /// the code of the most severe failure.
+    #[error("Failed to refresh {0} profile(s)")]
+    ProfilesFailed(usize, i32),
+    #[error(transparent)]
+    Okta(#[from] ClientError),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl Error {
+    /// The process exit code used to report this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Config(_) => 2,
+            Error::Authentication(_) => 3,
+            Error::MfaRejected(_) => 4,
+            Error::Saml(_) => 5,
+            Error::RoleNotFound(_) => 6,
+            Error::Sts(_) => 7,
+            Error::ProfilesFailed(_, exit_code) => *exit_code,
+            Error::Okta(_) => 8,
+            Error::Http(_) => 9,
+            Error::Io(_) => 10,
+        }
+    }
+
+    /// The error reporting that the organizations or profiles that failed with `errors` could
+    /// not be refreshed
+    pub fn profiles_failed(errors: &[&Error]) -> Error {
+        let exit_code = errors
+            .iter()
+            .max_by_key(|e| e.severity())
+            .map_or(1, |e| e.exit_code());
+
+        Error::ProfilesFailed(errors.len(), exit_code)
+    }
+
+    /// How much of the run an error is likely to affect: configuration and login failures
+    /// (which usually affect every profile) come before failures of a single profile
+    fn severity(&self) -> u8 {
+        match self {
+            Error::Config(_) => 9,
+            Error::Authentication(_) => 8,
+            Error::MfaRejected(_) => 7,
+            Error::Okta(_) => 6,
+            Error::Http(_) => 5,
+            Error::Io(_) => 4,
+            Error::Saml(_) => 3,
+            Error::RoleNotFound(_) => 2,
+            Error::Sts(_) => 1,
+            Error::ProfilesFailed(..) => 0,
+        }
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Self {
+        Error::Config(e.to_string())
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(e: toml::ser::Error) -> Self {
+        Error::Config(e.to_string())
+    }
+}
+
+impl From<url::ParseError> for Error {
+    fn from(e: url::ParseError) -> Self {
+        Error::Config(e.to_string())
+    }
+}
+
+impl From<serde_ini::de::Error> for Error {
+    fn from(e: serde_ini::de::Error) -> Self {
+        Error::Config(e.to_string())
+    }
+}
+
+impl From<serde_ini::ser::Error> for Error {
+    fn from(e: serde_ini::ser::Error) -> Self {
+        Error::Config(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Io(e.into())
+    }
+}
+
+impl From<path_abs::Error> for Error {
+    fn from(e: path_abs::Error) -> Self {
+        Error::Io(e.into())
+    }
+}
+
+impl From<base64::DecodeError> for Error {
+    fn from(e: base64::DecodeError) -> Self {
+        Error::Saml(e.to_string())
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(e: FromUtf8Error) -> Self {
+        Error::Saml(e.to_string())
+    }
+}
+
+impl From<TlsError> for Error {
+    fn from(e: TlsError) -> Self {
+        Error::Sts(e.to_string())
+    }
+}
+
+impl<E: std::error::Error + 'static> From<RusotoError<E>> for Error {
+    fn from(e: RusotoError<E>) -> Self {
+        Error::Sts(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_failed_exit_code() {
+        let role_not_found = Error::RoleNotFound(String::from("No matching role"));
+        let mfa_rejected = Error::MfaRejected(String::from("Okta Verify Push was rejected"));
+        let sts = Error::Sts(String::from("Access denied"));
+
+        // A single failure keeps its own exit code
+        assert_eq!(Error::profiles_failed(&[&role_not_found]).exit_code(), 6);
+        assert_eq!(
+            Error::profiles_failed(&[&role_not_found, &mfa_rejected, &sts]).exit_code(),
+            4
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use crate::error::Error;
+
+use std::io;
+#[cfg(unix)]
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+
+use tokio::process::{Child, Command};
+
+/// Run `command` with `variables` added to its environment, returning its exit code
+pub async fn run(command: &[String], variables: Vec<(&str, String)>) -> Result<i32, Error> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| Error::Config("No command given".to_string()))?;
+
+    debug!("Running {:?}", command);
+
+    let mut child = Command::new(program)
+        .args(args)
+        .envs(variables)
+        // A profile in the environment would take precedence over the credentials for some tools
+        .env_remove("AWS_PROFILE")
+        .spawn()
+        .map_err(|e| {
+            Error::Io(io::Error::new(
+                e.kind(),
+                format!("Could not run {} ({})", program, e),
+            ))
+        })?;
+
+    let status = wait_forwarding_signals(&mut child).await?;
+
+    debug!("{} exited with {}", program, status);
+
+    Ok(exit_code(status))
+}
+
+#[cfg(unix)]
+async fn wait_forwarding_signals(child: &mut Child) -> Result<ExitStatus, Error> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let pid = match child.id() {
+        Some(pid) => pid as libc::pid_t,
+        // The child has already exited, so there is nothing to forward signals to
+        None => return child.wait().await.map_err(Into::into),
+    };
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut quit = signal(SignalKind::quit())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    loop {
+        let signal = tokio::select! {
+            status = child.wait() => return status.map_err(Into::into),
+            _ = hangup.recv() => libc::SIGHUP,
+            _ = interrupt.recv() => libc::SIGINT,
+            _ = quit.recv() => libc::SIGQUIT,
+            _ = terminate.recv() => libc::SIGTERM,
+        };
+
+        debug!("Forwarding signal {} to process {}", signal, pid);
+
+        // SAFETY: `kill` has no memory safety requirements
+        unsafe {
+            libc::kill(pid, signal);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_forwarding_signals(child: &mut Child) -> Result<ExitStatus, Error> {
+    loop {
+        tokio::select! {
+            status = child.wait() => return status.map_err(Into::into),
+            // The console delivers Ctrl-C to the child as well, so we only need to outlive it
+            _ = tokio::signal::ctrl_c() => debug!("Waiting for child process to handle Ctrl-C"),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn exit_code(status: ExitStatus) -> i32 {
+    // Follow the shell convention for processes killed by a signal
+    status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
+
+#[cfg(not(unix))]
+fn exit_code(status: ExitStatus) -> i32 {
+    status.code().unwrap_or(1)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn passes_exit_code_and_environment() {
+        let command = vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            r#"test "$AWS_ACCESS_KEY_ID" = ACCESS_KEY && exit 3"#.to_string(),
+        ];
+
+        let code = run(
+            &command,
+            vec![("AWS_ACCESS_KEY_ID", "ACCESS_KEY".to_string())],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(code, 3);
+    }
+
+    #[tokio::test]
+    async fn fails_without_command() {
+        let err = run(&[], vec![]).await.unwrap_err();
+
+        assert_eq!(err.to_string(), "No command given");
+    }
+}
@@ -1,27 +1,44 @@
 #[macro_use]
-extern crate failure;
-#[macro_use]
 extern crate log;
 
 mod aws;
+mod cache;
 mod config;
+mod error;
+mod exec;
 mod okta;
+mod retry;
 mod saml;
+mod secrets;
+mod totp;
+mod tty;
 
-use crate::aws::credentials::CredentialsStore;
-use crate::config::Config;
+use crate::aws::credentials::{CredentialsStore, Profiles};
+use crate::aws::environment;
+use crate::aws::process::{ProcessCache, ProcessCredentials};
+use crate::config::organization::{self, Organization, OrganizationConfig};
+use crate::config::profile::Profile;
+use crate::config::{oktaws_home, Config};
+use crate::error::Error;
 use crate::okta::client::Client as OktaClient;
+use crate::okta::factors::FactorPreference;
 
-use std::collections::HashMap;
 use std::env;
+use std::fs::{create_dir_all, write};
+use std::process;
 use std::sync::{Arc, Mutex};
 
-use failure::Error;
+use dialoguer::Confirm;
 use glob::Pattern;
+use rusoto_sts::Credentials;
 use structopt::StructOpt;
+use url::Url;
 
 #[derive(StructOpt, Debug)]
 pub struct Args {
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+
     /// Profile to update
     #[structopt(default_value = "*", parse(try_from_str))]
     pub profiles: Pattern,
@@ -31,35 +48,90 @@ pub struct Args {
         short = "o",
         long = "organizations",
         default_value = "*",
-        parse(try_from_str)
+        parse(try_from_str),
+        global = true
     )]
     pub organizations: Pattern,
 
-    /// Forces new credentials
-    #[structopt(short = "f", long = "force-new")]
-    #[cfg(not(target_os = "linux"))]
+    /// Forces new credentials, asking for the Okta password again
+    #[structopt(short = "f", long = "force-new", global = true)]
     pub force_new: bool,
 
     /// Sets the level of verbosity
-    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences), global = true)]
     pub verbosity: usize,
 
-    /// Silence all output
-    #[structopt(short = "q", long = "quiet")]
+    /// Only show warnings and errors, not progress messages
+    #[structopt(short = "q", long = "quiet", global = true)]
     pub quiet: bool,
 
+    /// MFA factor to use without asking, such as push, sms or totp:google
+    #[structopt(long = "factor", parse(try_from_str), global = true)]
+    pub factor: Option<FactorPreference>,
+
     /// Fetch profiles asynchronously
     #[structopt(short = "a", long = "async")]
     pub asynchronous: bool,
+
+    /// Maximum number of profiles to fetch at once, when fetching asynchronously
+    #[structopt(long = "max-concurrency", default_value = "4")]
+    pub max_concurrency: usize,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Print credentials for a profile in the AWS `credential_process` format
+    Process {
+        /// Profile to print credentials for
+        profile: String,
+    },
+    /// Run a command with temporary credentials for a profile in its environment
+    Exec {
+        /// Profile to run the command as
+        profile: String,
+
+        /// Command to run, after a `--`
+        #[structopt(required = true, last = true)]
+        command: Vec<String>,
+    },
+    /// Print statements that export temporary credentials for a profile
+    Env {
+        /// Profile to export credentials for
+        profile: String,
+
+        /// Output format (sh, fish, powershell, dotenv or json)
+        #[structopt(long = "format", default_value = "sh", parse(try_from_str))]
+        format: environment::Format,
+    },
+    /// List the configured profiles and the state of their credentials
+    List {
+        /// Profiles to list
+        #[structopt(default_value = "*", parse(try_from_str))]
+        profiles: Pattern,
+
+        /// Also list the roles Okta offers for each profile
+        #[structopt(short = "r", long = "remote")]
+        remote: bool,
+    },
+    /// Create an organization file with profiles for the AWS applications assigned to you in Okta
+    Init {
+        /// Name of the Okta organization, as in `https://<organization>.okta.com`
+        organization: String,
+
+        /// Okta URL, if not `https://<organization>.okta.com`
+        #[structopt(long = "base-url", parse(try_from_str))]
+        base_url: Option<Url>,
+    },
 }
 
 #[paw::main]
 #[tokio::main]
-async fn main(args: Args) -> Result<(), Error> {
+async fn main(args: Args) {
     debug!("Args: {:?}", args);
 
     // Set Log Level
     let log_level = match args.verbosity {
+        _ if args.quiet => "warn",
         0 => "info",
         1 => "debug",
         _ => "trace",
@@ -67,10 +139,219 @@ async fn main(args: Args) -> Result<(), Error> {
     env::set_var("RUST_LOG", format!("{}={}", module_path!(), log_level));
     pretty_env_logger::init();
 
-    // Fetch config from files
+    if let Err(e) = run(args).await {
+        error!("{}", e);
+        process::exit(e.exit_code());
+    }
+}
+
+async fn run(args: Args) -> Result<(), Error> {
+    match args.command {
+        Some(Command::Process { ref profile }) => process(load_config()?, &args, profile).await,
+        Some(Command::Exec {
+            ref profile,
+            ref command,
+        }) => exec(load_config()?, &args, profile, command).await,
+        Some(Command::Env {
+            ref profile,
+            format,
+        }) => env(load_config()?, &args, profile, format).await,
+        Some(Command::List {
+            ref profiles,
+            remote,
+        }) => list(load_config()?, &args, profiles, remote).await,
+        // Initializing an organization must not depend on the existing configuration
+        Some(Command::Init {
+            ref organization,
+            ref base_url,
+        }) => init(&args, organization, base_url.clone()).await,
+        None => refresh(load_config()?, &args).await,
+    }
+}
+
+/// Log in to the Okta organization, preferring the MFA factor given on the command line
+async fn login(organization: &Organization, args: &Args) -> Result<OktaClient, Error> {
+    let mut client = OktaClient::new(
+        organization.name.clone(),
+        organization.base_url.clone(),
+        organization.username.clone(),
+        args.factor.clone().or_else(|| organization.factor.clone()),
+        organization.totp_secret.clone(),
+        organization.saml_private_key.clone(),
+        args.force_new,
+    )
+    .await?;
+    client.saml_certificate = organization.saml_certificate.clone();
+
+    Ok(client)
+}
+
+/// Fetch config from files
+fn load_config() -> Result<Config, Error> {
     let config = Config::new()?;
     debug!("Config: {:?}", config);
 
+    Ok(config)
+}
+
+/// Generate an organization file from the AWS applications the user has access to in Okta
+async fn init(args: &Args, organization_name: &str, base_url: Option<Url>) -> Result<(), Error> {
+    let path = oktaws_home()?.join(format!("{}.toml", organization_name));
+
+    tty::require("the organization's username and applications")?;
+
+    if path.exists()
+        && !Confirm::new()
+            .with_prompt(format!("Overwrite {}?", path.display()))
+            .default(false)
+            .interact()?
+    {
+        return Ok(());
+    }
+
+    let username = organization::prompt_username(&organization_name)?;
+
+    let okta_client = OktaClient::new(
+        organization_name.to_string(),
+        organization::base_url(base_url.clone(), organization_name)?,
+        username.clone(),
+        args.factor.clone(),
+        None,
+        None,
+        args.force_new,
+    )
+    .await?;
+
+    let organization_config = OrganizationConfig {
+        base_url,
+        username: Some(username),
+        ..OrganizationConfig::from_client(&okta_client).await?
+    };
+
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    write(&path, toml::to_string(&organization_config)?)?;
+
+    info!(
+        "Saved organization {} to {}",
+        organization_name,
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Print the configured profiles, optionally checking their roles against those Okta offers
+async fn list(config: Config, args: &Args, profiles: &Pattern, remote: bool) -> Result<(), Error> {
+    // Only read, so listing does not create the credentials file
+    let credentials = Profiles::read(&CredentialsStore::path()?)?;
+
+    for mut organization in config.into_organizations(args.organizations.clone()) {
+        organization
+            .profiles
+            .retain(|profile| profiles.matches(&profile.name));
+
+        if organization.profiles.is_empty() {
+            continue;
+        }
+
+        let okta_client = if remote {
+            match login(&organization, args).await {
+                Ok(okta_client) => Some(okta_client),
+                Err(e) => {
+                    error!("Could not log in to {}: {}", organization.name, e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let mut header = vec!["PROFILE", "APPLICATION", "ROLE", "DURATION", "CREDENTIALS"];
+        if okta_client.is_some() {
+            header.push("OFFERED ROLES");
+        }
+
+        let mut rows = vec![header.into_iter().map(str::to_string).collect::<Vec<_>>()];
+
+        for profile in &organization.profiles {
+            let mut role = profile
+                .role
+                .clone()
+                .or_else(|| profile.role_arn.clone())
+                .unwrap_or_else(|| "(not set)".to_string());
+
+            let offered_roles = match okta_client {
+                Some(ref okta_client) => match profile.roles(okta_client).await {
+                    Ok(roles) => {
+                        let configured = profile.role.is_some()
+                            || profile.account_id.is_some()
+                            || profile.role_arn.is_some();
+                        if configured && !roles.iter().any(|role| profile.matches_role(role)) {
+                            role.push_str(" (not offered)");
+                        }
+                        Some(
+                            roles
+                                .iter()
+                                .map(|role| role.role_name().unwrap_or(&role.role_arn))
+                                .collect::<Vec<_>>()
+                                .join(", "),
+                        )
+                    }
+                    Err(e) => Some(format!("error: {}", e)),
+                },
+                None => None,
+            };
+
+            let mut row = vec![
+                profile.name.clone(),
+                profile.application_name.clone(),
+                role,
+                profile
+                    .duration_seconds
+                    .map(|duration| format!("{}s", duration))
+                    .unwrap_or_else(|| "default".to_string()),
+                credentials.freshness(&profile.name).to_string(),
+            ];
+            row.extend(offered_roles);
+
+            rows.push(row);
+        }
+
+        println!("{} ({})", organization.name, organization.base_url);
+        print_table(&rows);
+    }
+
+    Ok(())
+}
+
+/// Print rows with their columns aligned, indented under a heading
+fn print_table(rows: &[Vec<String>]) {
+    let columns = rows.iter().map(Vec::len).max().unwrap_or_default();
+    let widths = (0..columns)
+        .map(|column| {
+            rows.iter()
+                .filter_map(|row| row.get(column))
+                .map(String::len)
+                .max()
+                .unwrap_or_default()
+        })
+        .collect::<Vec<_>>();
+
+    for row in rows {
+        let cells = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>();
+
+        println!("  {}", cells.join("  ").trim_end());
+    }
+}
+
+/// Update the AWS credentials file with credentials for every matching profile
+async fn refresh(config: Config, args: &Args) -> Result<(), Error> {
     // Set up a store for AWS credentials
     let credentials_store = Arc::new(Mutex::new(CredentialsStore::new()?));
 
@@ -79,51 +360,199 @@ async fn main(args: Args) -> Result<(), Error> {
         .peekable();
 
     if organizations.peek().is_none() {
-        bail!("No organizations found called {}", args.organizations);
+        return Err(Error::Config(format!(
+            "No organizations found called {}",
+            args.organizations
+        )));
     }
 
-    for organization in organizations {
+    // Organizations or profiles that could not be refreshed, with the reason why
+    let mut failures = Vec::new();
+
+    for mut organization in organizations {
         info!("Evaluating profiles in {}", organization.name);
 
-        let okta_client = OktaClient::new(
-            organization.name.clone(),
-            organization.username.clone(),
-            #[cfg(not(target_os = "linux"))]
-            args.force_new,
-        )
-        .await?;
+        organization
+            .profiles
+            .retain(|profile| args.profiles.matches(&profile.name));
+
+        if !args.force_new {
+            let store = credentials_store.lock().unwrap();
+            let refresh_window = organization.refresh_window;
+
+            organization.profiles.retain(|profile| {
+                let needs_refresh = store.profiles.needs_refresh(&profile.name, refresh_window);
+
+                if !needs_refresh {
+                    info!("Credentials for {} are still valid, skipping", profile.name);
+                }
 
-        let credentials_map = if args.asynchronous {
+                needs_refresh
+            });
+        }
+
+        if organization.profiles.is_empty() {
+            info!("No profiles need refreshing in {}", organization.name);
+            continue;
+        }
+
+        let okta_client = match login(&organization, args).await {
+            Ok(okta_client) => okta_client,
+            Err(e) => {
+                failures.push((organization.name.clone(), e));
+                continue;
+            }
+        };
+
+        let organization_name = organization.name.clone();
+
+        let credentials_results: Vec<_> = if args.asynchronous {
             organization
-                .into_credentials(&okta_client, args.profiles.clone())
+                .into_credentials(&okta_client, args.profiles.clone(), args.max_concurrency)
                 .await
                 .collect()
         } else {
             let profiles = organization.into_profiles(args.profiles.clone());
 
-            let mut credentials_map = HashMap::new();
+            let mut credentials_results = Vec::new();
             for profile in profiles {
                 let name = profile.name.clone();
 
                 info!("Requesting tokens for {}", profile.name);
 
-                let credentials = profile.into_credentials(&okta_client).await.unwrap();
+                let credentials = profile.into_credentials(&okta_client).await;
 
-                credentials_map.insert(name, credentials);
+                credentials_results.push((name, credentials));
             }
 
-            credentials_map
+            credentials_results
         };
 
-        for (name, creds) in credentials_map {
-            credentials_store
-                .lock()
-                .unwrap()
-                .profiles
-                .set_sts_credentials(name.clone(), creds.into())?;
+        for (name, result) in credentials_results {
+            // A profile whose credentials cannot be saved must not stop the others being saved
+            let saved = result.and_then(|creds| {
+                credentials_store
+                    .lock()
+                    .unwrap()
+                    .profiles
+                    .set_sts_credentials(name.clone(), creds.into())
+            });
+
+            if let Err(e) = saved {
+                failures.push((format!("{}/{}", organization_name, name), e));
+            }
         }
     }
 
     let mut store = credentials_store.lock().unwrap();
-    store.save()
+    store.save()?;
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        report_failures(&failures);
+        Err(Error::profiles_failed(
+            &failures.iter().map(|(_, e)| e).collect::<Vec<_>>(),
+        ))
+    }
+}
+
+/// Log a table of the organizations and profiles that could not be refreshed
+fn report_failures(failures: &[(String, Error)]) {
+    let width = failures
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or_default();
+
+    error!("Failed to refresh:");
+    for (name, e) in failures {
+        error!("  {:width$}  {}", name, e, width = width);
+    }
+}
+
+/// Print `credential_process` output for a single profile, reusing cached credentials if possible
+async fn process(config: Config, args: &Args, profile_name: &str) -> Result<(), Error> {
+    let (organization, profile) = config.into_profile(args.organizations.clone(), profile_name)?;
+
+    let cache = ProcessCache::new()?;
+
+    let credentials = match cache.get(&organization.name, &profile.name) {
+        Some(credentials)
+            if !args.force_new && !credentials.needs_refresh(organization.refresh_window) =>
+        {
+            debug!("Using cached credentials for {}", profile.name);
+            credentials
+        }
+        _ => {
+            let okta_client = login(&organization, args).await?;
+
+            info!("Requesting tokens for {}", profile.name);
+
+            let credentials: ProcessCredentials =
+                profile.into_credentials(&okta_client).await?.into();
+            cache.set(&organization.name, profile_name, &credentials)?;
+
+            credentials
+        }
+    };
+
+    println!("{}", serde_json::to_string(&credentials)?);
+
+    Ok(())
+}
+
+/// Run a command with credentials for a single profile, then exit with its exit code
+async fn exec(
+    config: Config,
+    args: &Args,
+    profile_name: &str,
+    command: &[String],
+) -> Result<(), Error> {
+    let (profile, credentials) = profile_credentials(config, args, profile_name).await?;
+
+    let code = exec::run(
+        command,
+        environment::variables(&credentials, profile.region.as_deref()),
+    )
+    .await?;
+
+    process::exit(code)
+}
+
+/// Print credentials for a single profile as environment variables
+async fn env(
+    config: Config,
+    args: &Args,
+    profile_name: &str,
+    format: environment::Format,
+) -> Result<(), Error> {
+    let (profile, credentials) = profile_credentials(config, args, profile_name).await?;
+
+    print!(
+        "{}",
+        format.render(&environment::variables(
+            &credentials,
+            profile.region.as_deref()
+        ))?
+    );
+
+    Ok(())
+}
+
+/// Log in to Okta and fetch fresh credentials for a single profile
+async fn profile_credentials(
+    config: Config,
+    args: &Args,
+    profile_name: &str,
+) -> Result<(Profile, Credentials), Error> {
+    let (organization, profile) = config.into_profile(args.organizations.clone(), profile_name)?;
+
+    let okta_client = login(&organization, args).await?;
+
+    info!("Requesting tokens for {}", profile.name);
+
+    let credentials = profile.clone().into_credentials(&okta_client).await?;
+
+    Ok((profile, credentials))
 }
@@ -1,12 +1,15 @@
+use crate::error::Error;
 use crate::okta::client::Client;
+use crate::okta::duo::Verification as DuoVerification;
 use crate::okta::factors::Factor;
 use crate::okta::users::User;
 use crate::okta::Links;
+use crate::tty;
 
 use dialoguer;
-use failure::{err_msg, Error};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use url::Url;
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -32,7 +35,11 @@ impl LoginRequest {
         Self {
             audience: None,
             context: None,
-            options: None,
+            // Okta only warns that the password is about to expire when asked to
+            options: Some(Options {
+                multi_optional_factor_enroll: false,
+                warn_before_password_expired: true,
+            }),
             password: Some(password),
             token: None,
             username: Some(username),
@@ -86,8 +93,12 @@ pub struct LoginResponse {
 pub enum FactorResult {
     Waiting,
     Success,
+    Challenge,
     Rejected,
     Timeout,
+    TimeWindowExceeded,
+    PasscodeReplayed,
+    Error,
 }
 
 #[derive(Deserialize, Debug)]
@@ -95,9 +106,89 @@ pub enum FactorResult {
 pub struct LoginEmbedded {
     #[serde(default)]
     factors: Vec<Factor>,
+    /// The factor being verified, while waiting for a challenge to be answered
+    factor: Option<ChallengedFactor>,
+    /// The password policy, when the password has or is about to expire
+    policy: Option<PasswordPolicy>,
     user: User,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct PasswordPolicy {
+    expiration: Option<PasswordExpiration>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PasswordExpiration {
+    password_expire_days: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChallengedFactor {
+    #[serde(rename = "_embedded")]
+    embedded: Option<ChallengedFactorEmbedded>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChallengedFactorEmbedded {
+    challenge: Option<Challenge>,
+    verification: Option<DuoVerification>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Challenge {
+    /// The number to choose on the phone, when Okta Verify uses number matching
+    correct_answer: Option<u32>,
+}
+
+impl LoginResponse {
+    /// The first link with the given relation, such as `resend`
+    pub fn link(&self, name: &str) -> Option<Url> {
+        match self.links.get(name)? {
+            Links::Single(link) => Some(link.href.clone()),
+            Links::Multi(links) => links.first().map(|link| link.href.clone()),
+        }
+    }
+
+    /// The Duo transaction to complete, when verifying a Duo factor
+    pub fn duo_verification(&self) -> Option<&DuoVerification> {
+        self.embedded
+            .as_ref()?
+            .factor
+            .as_ref()?
+            .embedded
+            .as_ref()?
+            .verification
+            .as_ref()
+    }
+
+    /// How many days are left until the password expires, when Okta warns about it
+    pub fn password_expire_days(&self) -> Option<u32> {
+        self.embedded
+            .as_ref()?
+            .policy
+            .as_ref()?
+            .expiration
+            .as_ref()?
+            .password_expire_days
+    }
+
+    /// The number the user must choose in Okta Verify to approve a Push
+    pub fn correct_answer(&self) -> Option<u32> {
+        self.embedded
+            .as_ref()?
+            .factor
+            .as_ref()?
+            .embedded
+            .as_ref()?
+            .challenge
+            .as_ref()?
+            .correct_answer
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum LoginState {
@@ -129,48 +220,247 @@ impl Client {
     }
 
     pub async fn get_session_token(&self, req: &LoginRequest) -> Result<String, Error> {
-        let response = self.login(req).await?;
-
-        trace!("Login response: {:?}", response);
-
-        match response.status {
-            LoginState::Success => Ok(response.session_token.unwrap()),
-            LoginState::MfaRequired => {
-                let factors = response.embedded.unwrap().factors;
-
-                let factor = match factors.len() {
-                    0 => bail!("MFA is required, but the user has no enrolled factors"),
-                    1 => {
-                        info!(
-                            "Only one MFA option is available ({}), using it",
-                            factors[0]
-                        );
-                        &factors[0]
+        let mut response = self.login(req).await?;
+
+        loop {
+            trace!("Login response: {:?}", response);
+
+            response = match response.status {
+                LoginState::Success => {
+                    return response.session_token.ok_or_else(|| {
+                        Error::Authentication("No session token found in response".to_string())
+                    })
+                }
+                // Okta may still warn about or require changing the password after MFA
+                LoginState::MfaRequired => self.verify_mfa(response).await?,
+                LoginState::PasswordWarn => self.skip_password_warning(response).await?,
+                LoginState::PasswordExpired => {
+                    self.change_expired_password(response, req.password.as_deref())
+                        .await?
+                }
+                LoginState::LockedOut => {
+                    return Err(self.locked_out(response, req.username.as_deref()).await)
+                }
+                ref status => {
+                    return Err(Error::Authentication(format!(
+                        "Unknown error encountered during login ({:?})",
+                        status
+                    )))
+                }
+            };
+        }
+    }
+
+    /// Verify one of the user's MFA factors, returning Okta's response to the verification
+    async fn verify_mfa(&self, response: LoginResponse) -> Result<LoginResponse, Error> {
+        let factors = response
+            .embedded
+            .ok_or_else(|| Error::Authentication("No MFA factors found in response".to_string()))?
+            .factors;
+
+        let preferred = match self.factor {
+            Some(ref preference) => {
+                let factor = factors.iter().find(|factor| preference.matches(factor));
+
+                if factor.is_none() {
+                    warn!("No enrolled MFA factor matches {}", preference);
+                }
+
+                factor
+            }
+            // A TOTP passcode can be generated without asking the user anything
+            None if self.totp_secret.is_some() => factors
+                .iter()
+                .find(|factor| matches!(factor, Factor::Totp { .. })),
+            None => None,
+        };
+
+        let factor = match (preferred, factors.len()) {
+            (Some(factor), _) => {
+                debug!("Using preferred MFA option ({})", factor);
+                factor
+            }
+            (None, 0) => {
+                return Err(Error::MfaRejected(
+                    "MFA is required, but the user has no enrolled factors".to_string(),
+                ))
+            }
+            (None, 1) => {
+                info!(
+                    "Only one MFA option is available ({}), using it",
+                    factors[0]
+                );
+                &factors[0]
+            }
+            (None, _) => {
+                tty::require(&format!(
+                    "an MFA factor (one of {}, which --factor can also choose)",
+                    factors
+                        .iter()
+                        .map(|factor| factor.preference().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))?;
+
+                let selection = dialoguer::Select::new()
+                    .with_prompt("Choose MFA Option")
+                    .items(&factors)
+                    .default(0)
+                    .interact()?;
+
+                &factors[selection]
+            }
+        };
+
+        debug!("Factor: {:?}", factor);
+
+        let state_token = response
+            .state_token
+            .ok_or_else(|| Error::Authentication("No state token found in response".to_string()))?;
+
+        let factor_provided_response = self.verify(factor, state_token).await?;
+
+        trace!("Factor Provided Response: {:?}", factor_provided_response);
+
+        Ok(factor_provided_response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::totp::TotpSecret;
+
+    use std::fs::{write, File};
+
+    use mockito::{mock, Matcher};
+    use serde_json::json;
+
+    #[test]
+    fn parse_push_challenge() {
+        let file = File::open("tests/fixtures/push_challenge_response.json").unwrap();
+
+        let response: LoginResponse = serde_json::from_reader(file).unwrap();
+
+        assert_eq!(response.factor_result, Some(FactorResult::Waiting));
+        assert_eq!(response.correct_answer(), Some(92));
+    }
+
+    #[test]
+    fn serialize_login_request() {
+        let request = LoginRequest::from_credentials(
+            "isaac.brock@example.com".to_string(),
+            "password".to_string(),
+        );
+
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            json!({
+                "options": {
+                    "multiOptionalFactorEnroll": false,
+                    "warnBeforePasswordExpired": true
+                },
+                "password": "password",
+                "username": "isaac.brock@example.com"
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn password_warning_after_mfa() {
+        let server_url = Url::parse(&mockito::server_url()).unwrap();
+
+        let user = json!({
+            "id": "00ub0oNGTSWTBKOLGLNR",
+            "profile": {
+                "login": "isaac.brock@example.com",
+                "firstName": "Isaac",
+                "lastName": "Brock",
+                "locale": "en_US",
+                "timeZone": "America/Los_Angeles"
+            }
+        });
+
+        let login = mock("POST", "/api/v1/authn")
+            .match_body(Matcher::PartialJson(json!({ "username": "isaac.brock@example.com" })))
+            .with_body(
+                json!({
+                    "stateToken": "STATE_TOKEN",
+                    "expiresAt": "2021-07-01T12:05:00.000Z",
+                    "status": "MFA_REQUIRED",
+                    "_embedded": {
+                        "user": user,
+                        "factors": [{
+                            "id": "ostf",
+                            "factorType": "token:software:totp",
+                            "provider": "GOOGLE",
+                            "profile": { "credentialId": "isaac.brock@example.com" },
+                            "_links": {
+                                "verify": {
+                                    "href": format!("{}api/v1/authn/factors/ostf/verify", server_url),
+                                    "hints": { "allow": ["POST"] }
+                                }
+                            }
+                        }]
                     }
-                    _ => {
-                        let selection = dialoguer::Select::new()
-                            .with_prompt("Choose MFA Option")
-                            .items(&factors)
-                            .default(0)
-                            .interact()?;
-
-                        &factors[selection]
+                })
+                .to_string(),
+            )
+            .create();
+
+        // Okta asks to confirm the password warning once MFA has been verified
+        let verify = mock("POST", "/api/v1/authn/factors/ostf/verify")
+            .match_query(Matcher::Any)
+            .match_body(Matcher::PartialJson(json!({ "stateToken": "STATE_TOKEN" })))
+            .with_body(
+                json!({
+                    "stateToken": "STATE_TOKEN",
+                    "expiresAt": "2021-07-01T12:05:00.000Z",
+                    "status": "PASSWORD_WARN",
+                    "_embedded": {
+                        "user": user,
+                        "policy": {
+                            "expiration": { "passwordExpireDays": 3 }
+                        }
                     }
-                };
+                })
+                .to_string(),
+            )
+            .create();
 
-                debug!("Factor: {:?}", factor);
+        let skip = mock("POST", "/api/v1/authn/lifecycle/skip")
+            .match_body(Matcher::Json(json!({ "stateToken": "STATE_TOKEN" })))
+            .with_body(
+                json!({
+                    "sessionToken": "SESSION_TOKEN",
+                    "expiresAt": "2021-07-01T12:05:00.000Z",
+                    "status": "SUCCESS",
+                    "_embedded": { "user": user }
+                })
+                .to_string(),
+            )
+            .create();
 
-                let state_token = response
-                    .state_token
-                    .ok_or_else(|| format_err!("No state token found in response"))?;
+        let tempdir = tempfile::tempdir().unwrap();
+        let secret_path = tempdir.path().join("totp_secret");
+        write(&secret_path, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap();
 
-                let factor_provided_response = self.verify(&factor, state_token).await?;
+        let mut client = Client::unauthenticated(server_url).unwrap();
+        client.totp_secret = Some(TotpSecret::File(secret_path));
 
-                trace!("Factor Provided Response: {:?}", factor_provided_response);
+        let session_token = client
+            .get_session_token(&LoginRequest::from_credentials(
+                "isaac.brock@example.com".to_string(),
+                "password".to_string(),
+            ))
+            .await
+            .unwrap();
 
-                Ok(factor_provided_response.session_token.unwrap())
-            }
-            _ => Err(err_msg("Unknown error encountered during login")),
-        }
+        assert_eq!(session_token, "SESSION_TOKEN");
+
+        login.assert();
+        verify.assert();
+        skip.assert();
     }
 }
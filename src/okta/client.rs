@@ -1,29 +1,59 @@
+use crate::error::Error;
 use crate::okta::auth::LoginRequest;
-
-use std::collections::HashSet;
-use std::sync::Arc;
+use crate::okta::factors::FactorPreference;
+use crate::okta::sessions::{CachedSession, Session, SessionCache};
+use crate::okta::users::AppLink;
+use crate::retry::{backoff, MAX_RETRIES};
+use crate::saml::signature::{Certificate, SamlCertificate};
+use crate::saml::Response as SamlResponse;
+use crate::secrets::KeyringEntry;
+use crate::totp::TotpSecret;
+use crate::tty;
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use dialoguer::Password;
-use failure::Error;
-#[cfg(not(target_os = "linux"))]
-use keyring::Keyring;
-use reqwest::cookie::Jar;
+use reqwest::cookie::{CookieStore, Jar};
 use reqwest::header::{HeaderValue, ACCEPT};
 use reqwest::Client as HttpClient;
-use reqwest::Response;
+use reqwest::{RequestBuilder, Response, StatusCode};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use thiserror::Error as ThisError;
+use tokio::sync::OnceCell;
+use tokio::time::sleep;
 use url::Url;
 
 pub struct Client {
     client: HttpClient,
     base_url: Url,
     pub cookies: Arc<Jar>,
+    /// The MFA factor to use without asking, if it is enrolled
+    pub(crate) factor: Option<FactorPreference>,
+    /// Where to read the secret for generating TOTP passcodes from
+    pub(crate) totp_secret: Option<TotpSecret>,
+    /// The private key to decrypt encrypted SAML assertions with
+    pub(crate) saml_private_key: Option<PathBuf>,
+    /// Where to reach Duo's API instead of `https://` and the host Okta names
+    pub(crate) duo_base_url: Option<Url>,
+    /// Where to get the certificate to verify SAML responses with
+    pub(crate) saml_certificate: Option<SamlCertificate>,
+    /// The certificate to verify SAML responses with, loaded at most once per run
+    pub(crate) saml_signing_certificate: OnceCell<Certificate>,
+    /// The password the user changed to while logging in, to be saved instead of the old one
+    pub(crate) new_password: Mutex<Option<String>>,
+    /// App links of the logged-in user, fetched at most once per run
+    pub(crate) app_links: OnceCell<Vec<AppLink>>,
+    /// SAML responses by application URL, shared by all profiles using that application
+    pub(crate) saml_responses: Mutex<HashMap<Url, Arc<OnceCell<Arc<SamlResponse>>>>>,
 }
 
-#[derive(Deserialize, Debug, Fail, Serialize)]
+#[derive(Deserialize, Debug, Serialize, ThisError)]
 #[serde(rename_all = "camelCase")]
-#[fail(display = "{}: {}", error_code, error_summary)]
+#[error("{error_code}: {error_summary}")]
 pub struct ClientError {
     error_code: String,
     error_summary: String,
@@ -38,42 +68,87 @@ pub struct ClientErrorSummary {
     error_summary: String,
 }
 
+impl ClientError {
+    pub fn is_authentication_failure(&self) -> bool {
+        self.error_summary == "Authentication failed"
+    }
+
+    /// Whether Okta refused the passcode or answer given for an MFA factor
+    pub fn is_factor_rejection(&self) -> bool {
+        // Invalid passcode or answer, and a passcode that has already been used
+        matches!(self.error_code.as_str(), "E0000068" | "E0000082")
+    }
+
+    /// Whether Okta refused a new password as it does not meet the password policy
+    pub fn is_password_policy_violation(&self) -> bool {
+        self.error_code == "E0000080"
+    }
+}
+
 impl Client {
     pub async fn new(
         organization: String,
+        mut base_url: Url,
         username: String,
-        #[cfg(not(target_os = "linux"))] force_prompt: bool,
+        factor: Option<FactorPreference>,
+        totp_secret: Option<TotpSecret>,
+        saml_private_key: Option<PathBuf>,
+        force_prompt: bool,
     ) -> Result<Self, Error> {
-        let mut base_url = Url::parse(&format!("https://{}.okta.com/", organization))?;
+        debug!("Using {} for organization {}", base_url, organization);
+
         base_url
             .set_username(&username)
-            .map_err(|_| format_err!("Cannot set username for URL"))?;
+            .map_err(|_| Error::Config("Cannot set username for URL".to_string()))?;
+
+        let mut client = Client::unauthenticated(base_url.clone())?;
+        client.factor = factor;
+        client.totp_secret = totp_secret;
+        client.saml_private_key = saml_private_key;
+
+        let session_cache = match SessionCache::new() {
+            Ok(session_cache) => Some(session_cache),
+            Err(e) => {
+                warn!("Okta sessions will not be kept between runs: {}", e);
+                None
+            }
+        };
 
-        let cookies = Arc::from(Jar::default());
+        if let Some(cached_session) = session_cache
+            .as_ref()
+            .and_then(|session_cache| session_cache.get(&organization))
+            .filter(|cached_session| cached_session.is_for(&client.base_url))
+        {
+            // Keep the device token even if the session has ended, so MFA can still be remembered
+            if let Some(ref device_token) = cached_session.device_token {
+                client
+                    .cookies
+                    .add_cookie_str(&format!("DT={}", device_token), &client.base_url);
+            }
 
-        let mut client = Client {
-            client: HttpClient::builder()
-                .cookie_store(true)
-                .cookie_provider(cookies.clone())
-                .build()?,
-            base_url: base_url.clone(),
-            cookies,
-        };
+            if !force_prompt && !cached_session.is_expired() {
+                client.set_session_id(cached_session.session_id);
+
+                match client.get_session().await {
+                    Ok(session) => {
+                        debug!("Reusing Okta session for {}", organization);
+                        client.save_session(session_cache.as_ref(), &organization, &session);
+
+                        return Ok(client);
+                    }
+                    Err(e) => debug!("Cached Okta session is no longer valid: {}", e),
+                }
+            }
+        }
 
         // Visit the homepage to get a DeviceToken (DT) cookie (used for persisting MFA information).
         client.get_response(base_url).await?;
 
-        #[cfg(not(target_os = "linux"))]
         let service = format!("oktaws::okta::{}", organization);
-
-        #[cfg(not(target_os = "linux"))]
-        let keyring = Keyring::new(&service, &username);
+        let mut keyring = KeyringEntry::new(&service, &username);
 
         // get password
-        #[cfg(not(target_os = "linux"))]
-        let password = client.get_password(&keyring, force_prompt)?;
-        #[cfg(target_os = "linux")]
-        let password = client.prompt_password()?;
+        let password = client.get_password(&mut keyring, force_prompt)?;
 
         let login_request = LoginRequest::from_credentials(username.to_owned(), password.clone());
 
@@ -81,50 +156,131 @@ impl Client {
         let session_token = match client.get_session_token(&login_request).await {
             Ok(session_token) => {
                 // Save the password.
-                #[cfg(not(target_os = "linux"))]
-                client.set_cached_password(&keyring, &password);
+                client.set_cached_password(&mut keyring, &password);
 
                 Ok(session_token)
             }
-            Err(wrapped_error) => {
-                if wrapped_error
-                    .downcast_ref::<ClientError>()
-                    .map(|e| e.error_summary.as_ref())
-                    == Some("Authentication failed")
-                {
-                    warn!("Authentication failed, re-prompting for Okta credentials");
-
-                    let password = client.prompt_password()?;
-                    let login_request =
-                        LoginRequest::from_credentials(username.to_owned(), password.clone());
-
-                    let session_token = client.get_session_token(&login_request).await?;
-
-                    // Save the password.
-                    #[cfg(not(target_os = "linux"))]
-                    client.set_cached_password(&keyring, &password);
-
-                    Ok(session_token)
-                } else {
-                    Err(wrapped_error)
-                }
+            Err(Error::Okta(ref e)) if e.is_authentication_failure() => {
+                warn!("Authentication failed, re-prompting for Okta credentials");
+
+                let password = client.prompt_password()?;
+                let login_request =
+                    LoginRequest::from_credentials(username.to_owned(), password.clone());
+
+                let session_token =
+                    client
+                        .get_session_token(&login_request)
+                        .await
+                        .map_err(|e| match e {
+                            Error::Okta(e) if e.is_authentication_failure() => {
+                                Error::Authentication(e.to_string())
+                            }
+                            e => e,
+                        })?;
+
+                // Save the password.
+                client.set_cached_password(&mut keyring, &password);
+
+                Ok(session_token)
             }
+            Err(e) => Err(e),
         }?;
 
-        client.new_session(session_token, &HashSet::new()).await?;
+        let session = client.new_session(session_token, &HashSet::new()).await?;
+        client.save_session(session_cache.as_ref(), &organization, &session);
 
         Ok(client)
     }
 
+    /// Keep the session and device token for later runs
+    fn save_session(
+        &self,
+        session_cache: Option<&SessionCache>,
+        organization: &str,
+        session: &Session,
+    ) {
+        if let Some(session_cache) = session_cache {
+            let cached_session = CachedSession {
+                session_id: session.id.clone(),
+                device_token: self.cookie("DT"),
+                expires_at: session.expires_at.clone(),
+                username: self.base_url.username().to_string(),
+                host: self.base_url.host_str().unwrap_or_default().to_string(),
+            };
+
+            // Don't treat this as a failure, as it is not a hard requirement
+            if let Err(e) = session_cache.set(organization, &cached_session) {
+                warn!("Error while saving Okta session: {}", e);
+            }
+        }
+    }
+
+    /// The value of a cookie Okta has set
+    fn cookie(&self, name: &str) -> Option<String> {
+        let cookies = self.cookies.cookies(&self.base_url)?;
+
+        cookies.to_str().ok()?.split("; ").find_map(|cookie| {
+            let (cookie_name, value) = cookie.split_once('=')?;
+            if cookie_name == name {
+                Some(value.to_string())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// A client for `base_url` that has not logged in yet
+    pub(crate) fn unauthenticated(base_url: Url) -> Result<Self, Error> {
+        let cookies = Arc::from(Jar::default());
+
+        Ok(Client {
+            client: HttpClient::builder()
+                .cookie_store(true)
+                .cookie_provider(cookies.clone())
+                .build()?,
+            base_url,
+            cookies,
+            factor: None,
+            totp_secret: None,
+            saml_private_key: None,
+            duo_base_url: None,
+            saml_certificate: None,
+            saml_signing_certificate: OnceCell::new(),
+            new_password: Mutex::new(None),
+            app_links: OnceCell::new(),
+            saml_responses: Mutex::new(HashMap::new()),
+        })
+    }
+
     pub fn set_session_id(&mut self, session_id: String) {
         self.cookies
             .add_cookie_str(&format!("sid={}", session_id), &self.base_url);
     }
 
+    /// Send the request built by `request`, retrying it while Okta is rate limiting us
+    async fn send<F>(&self, request: F) -> Result<Response, Error>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let mut attempt = 0;
+
+        loop {
+            let response = request().send().await?;
+
+            if response.status() != StatusCode::TOO_MANY_REQUESTS || attempt == MAX_RETRIES {
+                return Ok(response);
+            }
+
+            let delay = rate_limit_reset(&response).unwrap_or_else(|| backoff(attempt));
+            warn!("Rate limited by Okta, retrying in {}s", delay.as_secs_f32());
+
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
     pub async fn get_response(&self, url: Url) -> Result<Response, Error> {
-        self.client
-            .get(url)
-            .send()
+        self.send(|| self.client.get(url.clone()))
             .await?
             .error_for_status()
             .map_err(|e| e.into())
@@ -134,15 +290,18 @@ impl Client {
     where
         O: DeserializeOwned,
     {
-        self.client
-            .get(self.base_url.join(path)?)
-            .header(ACCEPT, HeaderValue::from_static("application/json"))
-            .send()
-            .await?
-            .error_for_status()?
-            .json()
-            .await
-            .map_err(|e| e.into())
+        let url = self.base_url.join(path)?;
+
+        self.send(|| {
+            self.client
+                .get(url.clone())
+                .header(ACCEPT, HeaderValue::from_static("application/json"))
+        })
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .map_err(|e| e.into())
     }
 
     pub async fn post<I, O>(&self, path: &str, body: &I) -> Result<O, Error>
@@ -159,11 +318,12 @@ impl Client {
         O: DeserializeOwned,
     {
         let resp = self
-            .client
-            .post(url)
-            .json(body)
-            .header(ACCEPT, HeaderValue::from_static("application/json"))
-            .send()
+            .send(|| {
+                self.client
+                    .post(url.clone())
+                    .json(body)
+                    .header(ACCEPT, HeaderValue::from_static("application/json"))
+            })
             .await?;
 
         if resp.status().is_success() {
@@ -173,15 +333,27 @@ impl Client {
         }
     }
 
+    pub async fn post_form(&self, url: Url, form: &[(&str, &str)]) -> Result<Response, Error> {
+        self.send(|| self.client.post(url.clone()).form(form))
+            .await?
+            .error_for_status()
+            .map_err(|e| e.into())
+    }
+
     fn prompt_password(&self) -> Result<String, Error> {
+        tty::require("your Okta password")?;
+
         Password::new()
             .with_prompt(&format!("Password for {}", self.base_url))
             .interact()
             .map_err(Into::into)
     }
 
-    #[cfg(not(target_os = "linux"))]
-    pub fn get_password(&self, keyring: &Keyring, force_prompt: bool) -> Result<String, Error> {
+    pub fn get_password(
+        &self,
+        keyring: &mut KeyringEntry,
+        force_prompt: bool,
+    ) -> Result<String, Error> {
         // If the user chooses to force new creds, prompt them for them
         if force_prompt {
             self.prompt_password()
@@ -193,18 +365,101 @@ impl Client {
         }
     }
 
-    #[cfg(not(target_os = "linux"))]
-    fn get_cached_password(&self, keyring: &Keyring) -> Option<String> {
-        keyring.get_password().ok()
+    fn get_cached_password(&self, keyring: &mut KeyringEntry) -> Option<String> {
+        match keyring.get() {
+            Ok(password) => password,
+            Err(e) => {
+                warn!("Error while reading saved credentials: {}", e);
+                None
+            }
+        }
     }
 
-    #[cfg(not(target_os = "linux"))]
-    pub fn set_cached_password(&self, keyring: &Keyring, password: &str) {
+    pub fn set_cached_password(&self, keyring: &mut KeyringEntry, password: &str) {
+        let password = self.password_to_save(password);
+
         debug!("Saving Okta credentials for {}", self.base_url);
 
         // Don't treat this as a failure, as it is not a hard requirement
-        if let Err(e) = keyring.set_password(password) {
+        if let Err(e) = keyring.set(&password) {
             warn!("Error while saving credentials: {}", e);
         }
     }
+
+    /// The password to save after logging in with `password`: the new one, if it was changed
+    pub(crate) fn password_to_save(&self, password: &str) -> String {
+        self.new_password
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_else(|| password.to_string())
+    }
+}
+
+/// How long until Okta's rate limit resets, from the `X-Rate-Limit-Reset` header
+fn rate_limit_reset(response: &Response) -> Option<Duration> {
+    let reset = response
+        .headers()
+        .get("X-Rate-Limit-Reset")?
+        .to_str()
+        .ok()?
+        .parse::<u64>()
+        .ok()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+
+    // Wait an extra second to be sure the limit has been reset
+    reset
+        .checked_sub(now)
+        .map(|seconds| Duration::from_secs(seconds + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    use mockito::mock;
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn retry_rate_limited_request() {
+        let server_url = Url::parse(&mockito::server_url()).unwrap();
+
+        let reset = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 1;
+
+        let rate_limited = mock("GET", "/api/v1/rate_limited")
+            .with_status(429)
+            .with_header("X-Rate-Limit-Reset", &reset.to_string())
+            .with_body(
+                json!({
+                    "errorCode": "E0000047",
+                    "errorSummary": "API call exceeded rate limit due to too many requests.",
+                    "errorLink": "E0000047",
+                    "errorId": "oaeJx3JuQQhRHq7l2ulMEXAMPLE",
+                    "errorCauses": []
+                })
+                .to_string(),
+            )
+            .create();
+        let retried = mock("GET", "/api/v1/rate_limited")
+            .with_body(json!({ "status": "ACTIVE" }).to_string())
+            .create();
+
+        let client = Client::unauthenticated(server_url).unwrap();
+
+        let started = Instant::now();
+        let response: Value = client.get("api/v1/rate_limited").await.unwrap();
+
+        assert_eq!(response["status"], "ACTIVE");
+        // Waited for the rate limit to reset, rather than only backing off
+        assert!(started.elapsed() >= Duration::from_secs(1));
+
+        rate_limited.assert();
+        retried.assert();
+    }
 }
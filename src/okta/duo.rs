@@ -0,0 +1,389 @@
+use crate::error::Error;
+use crate::okta::auth::{FactorResult, LoginResponse};
+use crate::okta::client::Client;
+use crate::okta::factors::FactorVerificationRequest;
+use crate::okta::Links;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use kuchiki::traits::TendrilSink;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use tokio::time::{sleep, Instant};
+use url::Url;
+
+/// How long to wait for the user to approve a Duo push, and for Okta to see it
+const POLL_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The Duo transaction Okta starts when a Duo factor is verified
+#[derive(Deserialize, Debug)]
+pub struct Verification {
+    host: String,
+    /// The transaction and application signatures, as `TX|...:APP|...`
+    signature: String,
+    #[serde(rename = "_links")]
+    links: HashMap<String, Links>,
+}
+
+/// How to authenticate with Duo
+#[derive(Debug)]
+pub enum DuoFactor {
+    Push,
+    Passcode(String),
+}
+
+#[derive(Deserialize, Debug)]
+struct DuoResponse<T> {
+    stat: String,
+    response: Option<T>,
+    message: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Prompt {
+    txid: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Status {
+    status: Option<String>,
+    result: Option<String>,
+    result_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Completion {
+    cookie: String,
+}
+
+impl Client {
+    /// Verify a Duo factor through Duo's web API, without a browser, then complete the login
+    /// with Okta
+    pub async fn verify_duo(
+        &self,
+        factor_id: &str,
+        url: Url,
+        state_token: String,
+        duo_factor: DuoFactor,
+    ) -> Result<LoginResponse, Error> {
+        let request = FactorVerificationRequest::Push {
+            state_token: state_token.clone(),
+        };
+
+        // Have Okta start a Duo transaction
+        let response: LoginResponse = self.post_absolute(url.clone(), &request).await?;
+
+        let verification = response
+            .duo_verification()
+            .ok_or_else(|| Error::MfaRejected("No Duo transaction found".to_string()))?;
+
+        let (tx_signature, app_signature) = verification
+            .signature
+            .split_once(':')
+            .ok_or_else(|| Error::MfaRejected("Unexpected Duo signature from Okta".to_string()))?;
+
+        let cookie = self
+            .duo_authenticate(verification, tx_signature, &url, duo_factor)
+            .await?;
+
+        let complete_url = verification
+            .link("complete")
+            .ok_or_else(|| Error::MfaRejected("No Duo callback found".to_string()))?;
+
+        // Hand the signed Duo response back to Okta, as the Duo iframe would
+        self.post_form(
+            complete_url,
+            &[
+                ("id", factor_id),
+                ("stateToken", &state_token),
+                ("sig_response", &format!("{}:{}", cookie, app_signature)),
+            ],
+        )
+        .await?;
+
+        let poll_url = response.link("next").unwrap_or(url);
+        let deadline = Instant::now() + POLL_TIMEOUT;
+
+        loop {
+            if Instant::now() >= deadline {
+                return Err(Error::MfaRejected(
+                    "Timed out waiting for Okta to accept the Duo authentication".to_string(),
+                ));
+            }
+
+            let response: LoginResponse = self.post_absolute(poll_url.clone(), &request).await?;
+
+            match response.factor_result {
+                Some(FactorResult::Waiting) => sleep(Duration::from_millis(100)).await,
+                None | Some(FactorResult::Success) => return Ok(response),
+                Some(result) => {
+                    return Err(Error::MfaRejected(format!(
+                        "Failed to verify with Duo MFA ({:?})",
+                        result
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Authenticate with Duo for the transaction signed by `tx_signature`, returning the signed
+    /// authentication cookie
+    async fn duo_authenticate(
+        &self,
+        verification: &Verification,
+        tx_signature: &str,
+        parent: &Url,
+        duo_factor: DuoFactor,
+    ) -> Result<String, Error> {
+        let duo_url = match self.duo_base_url {
+            Some(ref duo_base_url) => duo_base_url.clone(),
+            None => Url::parse(&format!("https://{}/", verification.host))?,
+        };
+
+        let mut auth_url = duo_url.join("frame/web/v1/auth")?;
+        auth_url
+            .query_pairs_mut()
+            .append_pair("tx", tx_signature)
+            .append_pair("parent", parent.as_str())
+            .append_pair("v", "2.6");
+
+        let auth_response = self
+            .post_form(auth_url, &[("parent", parent.as_str())])
+            .await?
+            .text()
+            .await?;
+
+        let sid = extract_sid(&auth_response)?;
+
+        let mut prompt = vec![
+            ("sid", sid.as_str()),
+            ("device", "phone1"),
+            ("out_of_date", "False"),
+        ];
+        match duo_factor {
+            DuoFactor::Push => prompt.push(("factor", "Duo Push")),
+            DuoFactor::Passcode(ref passcode) => {
+                prompt.push(("factor", "Passcode"));
+                prompt.push(("passcode", passcode));
+            }
+        }
+
+        let Prompt { txid } = self
+            .duo_post(duo_url.join("frame/prompt")?, &prompt)
+            .await?;
+
+        let mut shown_status = None;
+        let deadline = Instant::now() + POLL_TIMEOUT;
+
+        let result_url = loop {
+            if Instant::now() >= deadline {
+                return Err(Error::MfaRejected(
+                    "Timed out waiting for Duo authentication".to_string(),
+                ));
+            }
+
+            let status: Status = self
+                .duo_post(
+                    duo_url.join("frame/status")?,
+                    &[("sid", &sid), ("txid", &txid)],
+                )
+                .await?;
+
+            if status.status.is_some() && status.status != shown_status {
+                info!("Duo: {}", status.status.as_deref().unwrap_or_default());
+                shown_status = status.status.clone();
+            }
+
+            match (status.result.as_deref(), status.result_url) {
+                (Some("SUCCESS"), Some(result_url)) => break result_url,
+                (Some("SUCCESS"), None) | (None, _) => sleep(Duration::from_secs(1)).await,
+                (Some(result), _) => {
+                    return Err(Error::MfaRejected(format!(
+                        "Duo authentication failed ({}: {})",
+                        result,
+                        status.status.unwrap_or_default()
+                    )))
+                }
+            }
+        };
+
+        let Completion { cookie } = self
+            .duo_post(duo_url.join(&result_url)?, &[("sid", &sid)])
+            .await?;
+
+        Ok(cookie)
+    }
+
+    async fn duo_post<O>(&self, url: Url, form: &[(&str, &str)]) -> Result<O, Error>
+    where
+        O: DeserializeOwned,
+    {
+        let response: DuoResponse<O> = self.post_form(url, form).await?.json().await?;
+
+        match (response.stat.as_str(), response.response) {
+            ("OK", Some(response)) => Ok(response),
+            _ => Err(Error::MfaRejected(format!(
+                "Duo returned an error ({})",
+                response.message.unwrap_or(response.stat)
+            ))),
+        }
+    }
+}
+
+impl Verification {
+    fn link(&self, name: &str) -> Option<Url> {
+        match self.links.get(name)? {
+            Links::Single(link) => Some(link.href.clone()),
+            Links::Multi(links) => links.first().map(|link| link.href.clone()),
+        }
+    }
+}
+
+/// The Duo session ID from the authentication frame
+fn extract_sid(text: &str) -> Result<String, Error> {
+    let not_found = || Error::MfaRejected("No Duo session found".to_string());
+
+    let doc = kuchiki::parse_html().one(text);
+    let input_node = doc
+        .select("input[name='sid']")
+        .map_err(|_| not_found())?
+        .next()
+        .ok_or_else(not_found)?;
+
+    let attributes = &input_node.attributes.borrow();
+    attributes
+        .get("value")
+        .map(str::to_string)
+        .ok_or_else(not_found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mockito::{mock, Matcher};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn verify_with_duo_push() {
+        let server_url = Url::parse(&mockito::server_url()).unwrap();
+
+        let user = json!({
+            "id": "00ub0oNGTSWTBKOLGLNR",
+            "profile": {
+                "login": "isaac.brock@example.com",
+                "firstName": "Isaac",
+                "lastName": "Brock",
+                "locale": "en_US",
+                "timeZone": "America/Los_Angeles"
+            }
+        });
+
+        let verify = mock("POST", "/api/v1/authn/factors/dsf/verify")
+            .match_body(Matcher::Json(json!({ "stateToken": "STATE_TOKEN" })))
+            .with_body(
+                json!({
+                    "stateToken": "STATE_TOKEN",
+                    "expiresAt": "2021-07-01T12:05:00.000Z",
+                    "status": "MFA_CHALLENGE",
+                    "factorResult": "WAITING",
+                    "_embedded": {
+                        "user": user,
+                        "factor": {
+                            "id": "dsf",
+                            "factorType": "web",
+                            "provider": "DUO",
+                            "_embedded": {
+                                "verification": {
+                                    "host": "api-1a2b3c4d.duosecurity.com",
+                                    "signature": "TX|tx_signature:APP|app_signature",
+                                    "_links": {
+                                        "complete": {
+                                            "href": format!("{}api/v1/authn/factors/dsf/lifecycle/duoCallback", server_url),
+                                            "hints": { "allow": ["POST"] }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    },
+                    "_links": {
+                        "next": {
+                            "name": "poll",
+                            "href": format!("{}api/v1/authn/factors/dsf/verify/poll", server_url),
+                            "hints": { "allow": ["POST"] }
+                        }
+                    }
+                })
+                .to_string(),
+            )
+            .create();
+
+        let auth = mock("POST", "/frame/web/v1/auth")
+            .match_query(Matcher::UrlEncoded("tx".into(), "TX|tx_signature".into()))
+            .with_body(r#"<html><form><input type="hidden" name="sid" value="SID"></form></html>"#)
+            .create();
+
+        let prompt = mock("POST", "/frame/prompt")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("sid".into(), "SID".into()),
+                Matcher::UrlEncoded("factor".into(), "Duo Push".into()),
+            ]))
+            .with_body(r#"{"stat": "OK", "response": {"txid": "TXID"}}"#)
+            .create();
+
+        let status = mock("POST", "/frame/status")
+            .match_body(Matcher::UrlEncoded("txid".into(), "TXID".into()))
+            .with_body(
+                r#"{"stat": "OK", "response": {"status": "Success! Logging you in...", "result": "SUCCESS", "result_url": "/frame/status/TXID"}}"#,
+            )
+            .create();
+
+        let result = mock("POST", "/frame/status/TXID")
+            .with_body(r#"{"stat": "OK", "response": {"cookie": "AUTH|cookie"}}"#)
+            .create();
+
+        let callback = mock("POST", "/api/v1/authn/factors/dsf/lifecycle/duoCallback")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("id".into(), "dsf".into()),
+                Matcher::UrlEncoded("stateToken".into(), "STATE_TOKEN".into()),
+                Matcher::UrlEncoded(
+                    "sig_response".into(),
+                    "AUTH|cookie:APP|app_signature".into(),
+                ),
+            ]))
+            .create();
+
+        let poll = mock("POST", "/api/v1/authn/factors/dsf/verify/poll")
+            .with_body(
+                json!({
+                    "sessionToken": "SESSION_TOKEN",
+                    "expiresAt": "2021-07-01T12:05:00.000Z",
+                    "status": "SUCCESS",
+                    "_embedded": { "user": user }
+                })
+                .to_string(),
+            )
+            .create();
+
+        let mut client = Client::unauthenticated(server_url.clone()).unwrap();
+        client.duo_base_url = Some(server_url.clone());
+
+        let response = client
+            .verify_duo(
+                "dsf",
+                server_url.join("api/v1/authn/factors/dsf/verify").unwrap(),
+                "STATE_TOKEN".to_string(),
+                DuoFactor::Push,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.session_token.as_deref(), Some("SESSION_TOKEN"));
+
+        for mock in [verify, auth, prompt, status, result, callback, poll] {
+            mock.assert();
+        }
+    }
+}
@@ -1,18 +1,23 @@
+use crate::error::Error;
 use crate::okta::auth::FactorResult;
 use crate::okta::auth::LoginResponse;
 use crate::okta::client::Client;
+use crate::okta::duo::DuoFactor;
 use crate::okta::Links;
 use crate::okta::Links::Multi;
 use crate::okta::Links::Single;
+use crate::tty;
 
 use std::collections::HashMap;
 use std::fmt;
-use std::thread::sleep;
+use std::str::FromStr;
 use std::time::Duration;
 
-use dialoguer::Password;
-use failure::Error;
+use dialoguer::console::Term;
+use dialoguer::{Confirm, Password};
 use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use url::Url;
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "lowercase", tag = "factorType")]
@@ -103,6 +108,72 @@ pub enum FactorProvider {
     Yubico,
 }
 
+impl FactorProvider {
+    fn name(&self) -> &'static str {
+        match self {
+            FactorProvider::Okta => "okta",
+            FactorProvider::Rsa => "rsa",
+            FactorProvider::Symantec => "symantec",
+            FactorProvider::Google => "google",
+            FactorProvider::Duo => "duo",
+            FactorProvider::Yubico => "yubico",
+        }
+    }
+}
+
+/// The factor types a preference can name
+const FACTOR_TYPES: [&str; 8] = [
+    "push", "sms", "call", "token", "totp", "hotp", "question", "web",
+];
+
+/// A factor to use without asking, such as `push`, `sms` or `totp:google`
+#[derive(Clone, Debug, PartialEq)]
+pub struct FactorPreference {
+    factor_type: String,
+    provider: Option<String>,
+}
+
+impl FactorPreference {
+    pub fn matches(&self, factor: &Factor) -> bool {
+        let preference = factor.preference();
+
+        self.factor_type == preference.factor_type
+            && (self.provider.is_none() || self.provider == preference.provider)
+    }
+}
+
+impl FromStr for FactorPreference {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let factor_type = parts.next().unwrap_or_default().to_lowercase();
+        let provider = parts.next().map(str::to_lowercase);
+
+        if !FACTOR_TYPES.contains(&factor_type.as_str()) {
+            return Err(Error::Config(format!(
+                "Unknown MFA factor {} (expected one of {})",
+                s,
+                FACTOR_TYPES.join(", ")
+            )));
+        }
+
+        Ok(FactorPreference {
+            factor_type,
+            provider,
+        })
+    }
+}
+
+impl fmt::Display for FactorPreference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.provider {
+            Some(ref provider) => write!(f, "{}:{}", self.factor_type, provider),
+            None => write!(f, "{}", self.factor_type),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum FactorStatus {
@@ -160,7 +231,9 @@ pub enum FactorVerificationRequest {
     #[serde(rename_all = "camelCase")]
     Push { state_token: String },
     #[serde(rename_all = "camelCase")]
-    Question { answer: String },
+    Resend { state_token: String },
+    #[serde(rename_all = "camelCase")]
+    Question { state_token: String, answer: String },
     #[serde(rename_all = "camelCase")]
     Sms {
         state_token: String,
@@ -168,14 +241,42 @@ pub enum FactorVerificationRequest {
         pass_code: Option<String>,
     },
     #[serde(rename_all = "camelCase")]
-    Call { pass_code: Option<String> },
+    Call {
+        state_token: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pass_code: Option<String>,
+    },
     #[serde(rename_all = "camelCase")]
     Totp {
         state_token: String,
         pass_code: String,
     },
     #[serde(rename_all = "camelCase")]
-    Token { pass_code: String },
+    Token {
+        state_token: String,
+        pass_code: String,
+    },
+}
+
+impl Factor {
+    /// How to refer to this factor in a preference, such as `totp:google`
+    pub fn preference(&self) -> FactorPreference {
+        let (factor_type, provider) = match self {
+            Factor::Push { provider, .. } => ("push", provider),
+            Factor::Sms { provider, .. } => ("sms", provider),
+            Factor::Call { provider, .. } => ("call", provider),
+            Factor::Token { provider, .. } => ("token", provider),
+            Factor::Totp { provider, .. } => ("totp", provider),
+            Factor::Hotp { provider, .. } => ("hotp", provider),
+            Factor::Question { provider, .. } => ("question", provider),
+            Factor::Web { provider, .. } => ("web", provider),
+        };
+
+        FactorPreference {
+            factor_type: factor_type.to_string(),
+            provider: Some(provider.name().to_string()),
+        }
+    }
 }
 
 impl fmt::Display for Factor {
@@ -184,12 +285,20 @@ impl fmt::Display for Factor {
             Factor::Push { .. } => write!(f, "Okta Verify Push"),
             Factor::Sms { ref profile, .. } => write!(f, "Okta SMS to {}", profile.phone_number),
             Factor::Call { ref profile, .. } => write!(f, "Okta Call to {}", profile.phone_number),
-            Factor::Token { .. } => write!(f, "Okta One-time Password"),
+            Factor::Token { ref provider, .. } => {
+                write!(f, "One-time Password (from {:?})", provider)
+            }
             Factor::Totp { ref provider, .. } => {
                 write!(f, "Okta Time-based One-time Password (from {:?})", provider)
             }
             Factor::Hotp { .. } => write!(f, "Okta Hardware One-time Password"),
-            Factor::Question { ref profile, .. } => write!(f, "Question: {}", profile.question),
+            Factor::Question { ref profile, .. } => {
+                write!(f, "Question: {}", profile.question_text)
+            }
+            Factor::Web {
+                provider: FactorProvider::Duo,
+                ..
+            } => write!(f, "Duo Security"),
             Factor::Web { .. } => write!(f, "Okta Web"),
         }
     }
@@ -200,73 +309,429 @@ impl Client {
         &self,
         factor: &Factor,
         state_token: String,
+    ) -> Result<LoginResponse, Error> {
+        self.verify_factor(factor, state_token)
+            .await
+            .map_err(|e| match e {
+                Error::Okta(e) if e.is_factor_rejection() => {
+                    Error::MfaRejected(format!("Failed to verify with {} ({})", factor, e))
+                }
+                e => e,
+            })
+    }
+
+    async fn verify_factor(
+        &self,
+        factor: &Factor,
+        state_token: String,
     ) -> Result<LoginResponse, Error> {
         match factor {
             Factor::Push { links, .. } => {
-                let url = match links.get("verify").unwrap() {
-                    Single(ref link) => link.href.clone(),
-                    Multi(ref links) => links.first().unwrap().href.clone(),
-                };
-
-                let request = FactorVerificationRequest::Push { state_token };
+                let url = verify_url(factor, links)?;
 
                 // Trigger sending of Push
-                let mut response: LoginResponse = self.post_absolute(url.clone(), &request).await?;
+                let mut response: LoginResponse = self
+                    .post_absolute(
+                        url.clone(),
+                        &FactorVerificationRequest::Push { state_token },
+                    )
+                    .await?;
 
-                while Some(FactorResult::Waiting) == response.factor_result {
-                    sleep(Duration::from_millis(100));
-                    response = self.post_absolute(url.clone(), &request).await?;
-                }
+                loop {
+                    let mut shown_answer = None;
+
+                    while Some(FactorResult::Waiting) == response.factor_result {
+                        // With number matching, the phone asks which number is shown here
+                        if let Some(answer) = response.correct_answer() {
+                            if shown_answer != Some(answer) {
+                                // A prompt rather than a log, so that it is shown even when quiet
+                                Term::stderr().write_line(&format!(
+                                    "Choose {} in Okta Verify to sign in",
+                                    answer
+                                ))?;
+                                shown_answer = Some(answer);
+                            }
+                        }
+
+                        let request = FactorVerificationRequest::Push {
+                            state_token: next_state_token(&response)?,
+                        };
+
+                        sleep(Duration::from_millis(100)).await;
+                        response = self.post_absolute(url.clone(), &request).await?;
+                    }
+
+                    let outcome = match response.factor_result {
+                        None | Some(FactorResult::Success) => return Ok(response),
+                        Some(FactorResult::Rejected) => "was rejected",
+                        Some(FactorResult::Timeout) => "timed out",
+                        Some(result) => {
+                            return Err(Error::MfaRejected(format!(
+                                "Failed to verify with Push MFA ({:?})",
+                                result
+                            )))
+                        }
+                    };
 
-                match response.factor_result {
-                    None | Some(FactorResult::Success) => Ok(response),
-                    Some(result) => bail!("Failed to verify with Push MFA ({:?})", result),
+                    tty::require("whether to send another push")?;
+
+                    let retry = Confirm::new()
+                        .with_prompt(format!("Okta Verify Push {}, send another?", outcome))
+                        .default(true)
+                        .interact()?;
+
+                    if !retry {
+                        return Err(Error::MfaRejected(format!("Okta Verify Push {}", outcome)));
+                    }
+
+                    info!("Sending another push ({})", factor);
+
+                    response = self.resend(&url, &response).await?;
                 }
             }
             Factor::Sms { links, .. } => {
-                let url = match links.get("verify").unwrap() {
-                    Single(ref link) => link.href.clone(),
-                    Multi(ref links) => links.first().unwrap().href.clone(),
+                let url = verify_url(factor, links)?;
+
+                self.verify_sent_passcode(factor, url, state_token, |state_token, pass_code| {
+                    FactorVerificationRequest::Sms {
+                        state_token,
+                        pass_code,
+                    }
+                })
+                .await
+            }
+            Factor::Call { links, .. } => {
+                let url = verify_url(factor, links)?;
+
+                self.verify_sent_passcode(factor, url, state_token, |state_token, pass_code| {
+                    FactorVerificationRequest::Call {
+                        state_token,
+                        pass_code,
+                    }
+                })
+                .await
+            }
+            Factor::Totp { links, .. } => {
+                let mut url = verify_url(factor, links)?;
+
+                url.set_query(Some("rememberDevice"));
+
+                let pass_code = match self.totp_secret {
+                    Some(ref totp_secret) => {
+                        debug!("Generating passcode for {}", factor);
+                        totp_secret.passcode()?
+                    }
+                    None => {
+                        tty::require("a TOTP passcode")?;
+
+                        Password::new().with_prompt(factor.to_string()).interact()?
+                    }
                 };
 
-                let request = FactorVerificationRequest::Sms {
+                let request = FactorVerificationRequest::Totp {
                     state_token,
-                    pass_code: None,
+                    pass_code,
                 };
 
-                // Trigger sending of SMS
-                let response: LoginResponse = self.post_absolute(url.clone(), &request).await?;
+                self.post_absolute(url, &request).await
+            }
+            Factor::Token { links, .. } | Factor::Hotp { links, .. } => {
+                let url = verify_url(factor, links)?;
 
-                let state_token = response
-                    .state_token
-                    .ok_or_else(|| format_err!("No state token found in factor prompt response"))?;
+                tty::require("a passcode")?;
 
-                let request = FactorVerificationRequest::Sms {
+                let request = FactorVerificationRequest::Token {
                     state_token,
-                    pass_code: Some(Password::new().with_prompt(factor.to_string()).interact()?),
+                    pass_code: Password::new().with_prompt(factor.to_string()).interact()?,
                 };
 
                 self.post_absolute(url, &request).await
             }
-            Factor::Totp { links, .. } => {
-                let mut url = match links.get("verify").unwrap() {
-                    Single(ref link) => link.href.clone(),
-                    Multi(ref links) => links.first().unwrap().href.clone(),
-                };
+            Factor::Question { links, profile, .. } => {
+                let url = verify_url(factor, links)?;
 
-                url.set_query(Some("rememberDevice"));
+                tty::require("the answer to a security question")?;
 
-                let request = FactorVerificationRequest::Totp {
+                let request = FactorVerificationRequest::Question {
                     state_token,
-                    pass_code: Password::new().with_prompt(factor.to_string()).interact()?,
+                    answer: Password::new()
+                        .with_prompt(&profile.question_text)
+                        .interact()?,
                 };
 
                 self.post_absolute(url, &request).await
             }
-            _ => {
+            Factor::Web {
+                id,
+                provider: FactorProvider::Duo,
+                links,
+                ..
+            } => {
+                let url = verify_url(factor, links)?;
+
+                tty::require("a Duo passcode")?;
+
+                let passcode = Password::new()
+                    .with_prompt("Duo passcode (leave empty to send a push)")
+                    .allow_empty_password(true)
+                    .interact()?;
+
+                let duo_factor = if passcode.is_empty() {
+                    DuoFactor::Push
+                } else {
+                    DuoFactor::Passcode(passcode)
+                };
+
+                self.verify_duo(id, url, state_token, duo_factor).await
+            }
+            Factor::Web { .. } => {
                 // TODO
-                bail!("Unsupported MFA method ({})", factor)
+                Err(Error::MfaRejected(format!(
+                    "Unsupported MFA method ({})",
+                    factor
+                )))
             }
         }
     }
+
+    /// Have Okta send a passcode (by SMS or call) and verify it, sending another when the user
+    /// asks for one
+    async fn verify_sent_passcode(
+        &self,
+        factor: &Factor,
+        url: Url,
+        state_token: String,
+        request: fn(String, Option<String>) -> FactorVerificationRequest,
+    ) -> Result<LoginResponse, Error> {
+        // Trigger sending of the passcode
+        let mut response: LoginResponse = self
+            .post_absolute(url.clone(), &request(state_token, None))
+            .await?;
+
+        loop {
+            tty::require("the passcode Okta sent")?;
+
+            let pass_code = Password::new()
+                .with_prompt(format!("{} (leave empty to resend)", factor))
+                .allow_empty_password(true)
+                .interact()?;
+
+            if !pass_code.is_empty() {
+                return self
+                    .post_absolute(url, &request(next_state_token(&response)?, Some(pass_code)))
+                    .await;
+            }
+
+            info!("Sending another passcode ({})", factor);
+
+            response = self.resend(&url, &response).await?;
+        }
+    }
+
+    /// Have Okta send another push or passcode, using the resend link of its last response
+    async fn resend(&self, url: &Url, response: &LoginResponse) -> Result<LoginResponse, Error> {
+        let resend_url = match response.link("resend") {
+            Some(resend_url) => resend_url,
+            None => Url::parse(&format!("{}/resend", url))?,
+        };
+
+        let request = FactorVerificationRequest::Resend {
+            state_token: next_state_token(response)?,
+        };
+
+        self.post_absolute(resend_url, &request).await
+    }
+}
+
+/// The state token to continue verifying a factor with
+fn next_state_token(response: &LoginResponse) -> Result<String, Error> {
+    response.state_token.clone().ok_or_else(|| {
+        Error::Authentication("No state token found in factor prompt response".to_string())
+    })
+}
+
+/// The URL to verify a factor at
+fn verify_url(factor: &Factor, links: &HashMap<String, Links>) -> Result<Url, Error> {
+    let link = match links.get("verify") {
+        Some(Single(ref link)) => Some(link),
+        Some(Multi(ref links)) => links.first(),
+        None => None,
+    };
+
+    link.map(|link| link.href.clone())
+        .ok_or_else(|| Error::MfaRejected(format!("No verification link found for {}", factor)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::totp::TotpSecret;
+
+    use std::fs::write;
+
+    use mockito::{mock, Matcher};
+    use serde_json::json;
+
+    #[test]
+    fn parse_factor_preference() {
+        let preference: FactorPreference = "TOTP:Google".parse().unwrap();
+
+        assert_eq!(preference.to_string(), "totp:google");
+        assert_eq!("push".parse::<FactorPreference>().unwrap().provider, None);
+        assert_eq!(
+            "email".parse::<FactorPreference>().unwrap_err().to_string(),
+            "Unknown MFA factor email (expected one of push, sms, call, token, totp, hotp, question, web)"
+        );
+    }
+
+    #[test]
+    fn match_factor_preference() {
+        let factors: Vec<Factor> = serde_json::from_str(
+            r#"[
+                {"factorType": "push", "provider": "OKTA", "id": "push", "_links": {}},
+                {"factorType": "token:software:totp", "provider": "GOOGLE", "id": "totp", "profile": {"credentialId": "user"}, "_links": {}}
+            ]"#,
+        )
+        .unwrap();
+
+        let totp: FactorPreference = "totp".parse().unwrap();
+        let google_totp: FactorPreference = "totp:google".parse().unwrap();
+        let okta_totp: FactorPreference = "totp:okta".parse().unwrap();
+
+        assert!(!totp.matches(&factors[0]));
+        assert!(totp.matches(&factors[1]));
+        assert!(google_totp.matches(&factors[1]));
+        assert!(!okta_totp.matches(&factors[1]));
+    }
+
+    #[test]
+    fn serialize_verification_requests() {
+        let call = FactorVerificationRequest::Call {
+            state_token: "STATE_TOKEN".to_string(),
+            pass_code: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&call).unwrap(),
+            r#"{"stateToken":"STATE_TOKEN"}"#
+        );
+
+        let token = FactorVerificationRequest::Token {
+            state_token: "STATE_TOKEN".to_string(),
+            pass_code: "123456".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&token).unwrap(),
+            r#"{"stateToken":"STATE_TOKEN","passCode":"123456"}"#
+        );
+
+        let question = FactorVerificationRequest::Question {
+            state_token: "STATE_TOKEN".to_string(),
+            answer: "answer".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&question).unwrap(),
+            r#"{"stateToken":"STATE_TOKEN","answer":"answer"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_passcode() {
+        let server_url = Url::parse(&mockito::server_url()).unwrap();
+
+        let verify = mock("POST", "/api/v1/authn/factors/rejected/verify")
+            .match_query(Matcher::Any)
+            .with_status(403)
+            .with_body(
+                json!({
+                    "errorCode": "E0000068",
+                    "errorSummary": "Invalid Passcode/Answer",
+                    "errorLink": "E0000068",
+                    "errorId": "oaei_IfXcpnTHit_YEKGInpFw",
+                    "errorCauses": []
+                })
+                .to_string(),
+            )
+            .create();
+
+        let factor: Factor = serde_json::from_value(json!({
+            "factorType": "token:software:totp",
+            "provider": "GOOGLE",
+            "id": "rejected",
+            "profile": { "credentialId": "isaac.brock@example.com" },
+            "_links": {
+                "verify": {
+                    "href": format!("{}api/v1/authn/factors/rejected/verify", server_url),
+                    "hints": { "allow": ["POST"] }
+                }
+            }
+        }))
+        .unwrap();
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let secret_path = tempdir.path().join("totp_secret");
+        write(&secret_path, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap();
+
+        let mut client = Client::unauthenticated(server_url).unwrap();
+        client.totp_secret = Some(TotpSecret::File(secret_path));
+
+        let err = client
+            .verify(&factor, "STATE_TOKEN".to_string())
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::MfaRejected(_)));
+        assert_eq!(err.exit_code(), 4);
+
+        verify.assert();
+    }
+
+    #[tokio::test]
+    async fn resend_rejected_push() {
+        let server_url = Url::parse(&mockito::server_url()).unwrap();
+
+        let resend = mock(
+            "POST",
+            "/api/v1/authn/factors/opf3hkfocI4JTLAju0g4/verify/resend",
+        )
+        .match_body(Matcher::Json(json!({ "stateToken": "STATE_TOKEN" })))
+        .with_body(
+            json!({
+                "stateToken": "STATE_TOKEN",
+                "expiresAt": "2015-11-03T10:15:57.000Z",
+                "status": "MFA_CHALLENGE",
+                "factorResult": "WAITING",
+                "_links": {}
+            })
+            .to_string(),
+        )
+        .create();
+
+        let verify_url = server_url
+            .join("api/v1/authn/factors/opf3hkfocI4JTLAju0g4/verify")
+            .unwrap();
+
+        let rejected: LoginResponse = serde_json::from_value(json!({
+            "stateToken": "STATE_TOKEN",
+            "expiresAt": "2015-11-03T10:15:57.000Z",
+            "status": "MFA_CHALLENGE",
+            "factorResult": "REJECTED",
+            "_links": {
+                "resend": [{
+                    "name": "push",
+                    "href": format!("{}/resend", verify_url),
+                    "hints": { "allow": ["POST"] }
+                }]
+            }
+        }))
+        .unwrap();
+
+        let client = Client::unauthenticated(server_url).unwrap();
+
+        let response = client.resend(&verify_url, &rejected).await.unwrap();
+
+        assert_eq!(response.factor_result, Some(FactorResult::Waiting));
+
+        resend.assert();
+    }
 }
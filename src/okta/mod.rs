@@ -1,18 +1,22 @@
 pub mod auth;
 pub mod client;
+pub mod duo;
 pub mod factors;
+pub mod passwords;
 pub mod sessions;
 pub mod users;
 
+use crate::error::Error;
 use crate::okta::auth::LoginRequest;
 use crate::okta::client::Client;
+use crate::saml::signature::{Certificate, SamlCertificate};
 use crate::saml::Response as SamlResponse;
 
-use std::convert::TryFrom;
+use std::path::Path;
 use std::str;
+use std::sync::Arc;
 
 use async_recursion::async_recursion;
-use failure::{Compat, Error};
 use kuchiki::traits::TendrilSink;
 use regex::Regex;
 use serde::Deserialize;
@@ -40,8 +44,23 @@ pub struct Hint {
 }
 
 impl Client {
+    /// Get the SAML response for an application, only requesting it from Okta once per run
+    pub async fn get_saml_response(&self, app_url: Url) -> Result<Arc<SamlResponse>, Error> {
+        let cell = self
+            .saml_responses
+            .lock()
+            .unwrap()
+            .entry(app_url.clone())
+            .or_default()
+            .clone();
+
+        cell.get_or_try_init(|| async { self.fetch_saml_response(app_url).await.map(Arc::new) })
+            .await
+            .map(Arc::clone)
+    }
+
     #[async_recursion]
-    pub async fn get_saml_response(&self, app_url: Url) -> Result<SamlResponse, Error> {
+    async fn fetch_saml_response(&self, app_url: Url) -> Result<SamlResponse, Error> {
         let response = self.get_response(app_url.clone()).await?.text().await?;
 
         trace!("SAML response doc for app {:?}: {}", &app_url, &response);
@@ -52,38 +71,69 @@ impl Client {
             let state_token = extract_state_token(&response)?;
             self.get_session_token(&LoginRequest::from_state_token(state_token))
                 .await?;
-            self.get_saml_response(app_url).await
+            self.fetch_saml_response(app_url).await
         } else {
-            extract_saml_response(response).map_err(|e| e.into())
+            extract_saml_response(
+                response,
+                self.saml_private_key.as_deref(),
+                self.saml_certificate().await?,
+            )
         }
     }
+
+    /// The certificate to verify SAML responses with, if one is configured
+    async fn saml_certificate(&self) -> Result<Option<&Certificate>, Error> {
+        let saml_certificate = match self.saml_certificate {
+            Some(ref saml_certificate) => saml_certificate,
+            None => return Ok(None),
+        };
+
+        self.saml_signing_certificate
+            .get_or_try_init(|| async {
+                match saml_certificate {
+                    SamlCertificate::File(path) => Certificate::from_file(path),
+                    SamlCertificate::Metadata(url) => {
+                        debug!("Fetching SAML metadata from {}", url);
+
+                        let metadata = self.get_response(url.clone()).await?.text().await?;
+                        Certificate::from_metadata(&metadata)
+                    }
+                }
+            })
+            .await
+            .map(Some)
+    }
 }
 
 fn extract_state_token(text: &str) -> Result<String, Error> {
-    let re = Regex::new(r#"var stateToken = '(.+)';"#)?;
+    let re = Regex::new(r#"var stateToken = '(.+)';"#).unwrap();
 
     if let Some(cap) = re.captures(text) {
         Ok(cap[1].to_owned().replace("\\x2D", "-"))
     } else {
-        bail!("No state token found")
+        Err(Error::Saml("No state token found".to_string()))
     }
 }
 
-pub fn extract_saml_response(text: String) -> Result<SamlResponse, ExtractSamlResponseError> {
+pub fn extract_saml_response(
+    text: String,
+    private_key: Option<&Path>,
+    certificate: Option<&Certificate>,
+) -> Result<SamlResponse, Error> {
+    let not_found = || Error::Saml("No SAML found".to_string());
+
     let doc = kuchiki::parse_html().one(text);
     let input_node = doc
         .select("input[name='SAMLResponse']")
-        .map_err(|_| ExtractSamlResponseError::NotFound)?
+        .map_err(|_| not_found())?
         .next()
-        .ok_or(ExtractSamlResponseError::NotFound)?;
+        .ok_or_else(not_found)?;
 
     let attributes = &input_node.attributes.borrow();
-    let saml = attributes
-        .get("value")
-        .ok_or(ExtractSamlResponseError::NotFound)?;
+    let saml = attributes.get("value").ok_or_else(not_found)?;
 
     trace!("SAML: {}", saml);
-    SamlResponse::try_from(saml.to_owned()).map_err(Into::into)
+    SamlResponse::validated(saml.to_owned(), private_key, certificate)
 }
 
 pub fn is_extra_verification(text: String) -> bool {
@@ -99,17 +149,3 @@ pub fn is_extra_verification(text: String) -> bool {
 
     false
 }
-
-#[derive(Fail, Debug)]
-pub enum ExtractSamlResponseError {
-    #[fail(display = "No SAML found")]
-    NotFound,
-    #[fail(display = "{}", _0)]
-    Invalid(#[cause] Compat<Error>),
-}
-
-impl From<Error> for ExtractSamlResponseError {
-    fn from(e: Error) -> ExtractSamlResponseError {
-        ExtractSamlResponseError::Invalid(e.compat())
-    }
-}
@@ -0,0 +1,345 @@
+use crate::error::Error;
+use crate::okta::auth::{LoginRequest, LoginResponse};
+use crate::okta::client::Client;
+use crate::tty;
+
+use dialoguer::{Confirm, Password};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ChangePasswordRequest<'a> {
+    state_token: &'a str,
+    old_password: &'a str,
+    new_password: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct UnlockRequest<'a> {
+    username: &'a str,
+    factor_type: &'a str,
+}
+
+#[derive(Deserialize, Debug)]
+struct UnlockResponse {}
+
+impl Client {
+    /// Continue logging in when the password is about to expire, without changing it
+    pub async fn skip_password_warning(
+        &self,
+        response: LoginResponse,
+    ) -> Result<LoginResponse, Error> {
+        match response.password_expire_days() {
+            Some(days) => warn!("Your Okta password expires in {} day(s)", days),
+            None => warn!("Your Okta password expires soon"),
+        }
+
+        let state_token = state_token(response.state_token)?;
+
+        self.post(
+            "api/v1/authn/lifecycle/skip",
+            &LoginRequest::from_state_token(state_token),
+        )
+        .await
+    }
+
+    /// Have the user choose a new password, as theirs has expired
+    pub async fn change_expired_password(
+        &self,
+        response: LoginResponse,
+        old_password: Option<&str>,
+    ) -> Result<LoginResponse, Error> {
+        warn!("Your Okta password has expired and must be changed");
+
+        let state_token = state_token(response.state_token)?;
+
+        tty::require("a new Okta password")?;
+
+        let old_password = match old_password {
+            Some(old_password) => old_password.to_string(),
+            None => Password::new().with_prompt("Current password").interact()?,
+        };
+
+        loop {
+            let new_password = Password::new()
+                .with_prompt("New password")
+                .with_confirmation("Repeat new password", "Passwords do not match")
+                .interact()?;
+
+            match self
+                .change_password(&state_token, &old_password, new_password)
+                .await
+            {
+                Ok(response) => return Ok(response),
+                // Let the user try again with a password that meets the policy
+                Err(Error::Okta(e)) if e.is_password_policy_violation() => {
+                    warn!("Could not change password: {}", e)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Change the expired password, keeping the new one to be saved instead of the old one
+    async fn change_password(
+        &self,
+        state_token: &str,
+        old_password: &str,
+        new_password: String,
+    ) -> Result<LoginResponse, Error> {
+        let request = ChangePasswordRequest {
+            state_token,
+            old_password,
+            new_password: &new_password,
+        };
+
+        let response = self
+            .post("api/v1/authn/credentials/change_password", &request)
+            .await?;
+
+        info!("Your Okta password has been changed");
+        *self.new_password.lock().unwrap() = Some(new_password);
+
+        Ok(response)
+    }
+
+    /// The error to report when the user is locked out, after offering a self-service unlock
+    pub async fn locked_out(&self, response: LoginResponse, username: Option<&str>) -> Error {
+        let locked_out = || {
+            Error::Authentication(
+                "Your Okta account is locked out, contact your administrator to unlock it"
+                    .to_string(),
+            )
+        };
+
+        // Self-service unlock is only linked to when the organization allows it
+        let username = match (response.link("unlock"), username) {
+            (Some(_), Some(username)) if tty::is_tty() => username,
+            _ => return locked_out(),
+        };
+
+        match Confirm::new()
+            .with_prompt("Your Okta account is locked out. Send an email to unlock it?")
+            .default(true)
+            .interact()
+        {
+            Ok(true) => {}
+            Ok(false) => return locked_out(),
+            Err(e) => return e.into(),
+        }
+
+        self.unlock(username).await
+    }
+
+    /// Have Okta email the user a link to unlock their account, returning the error to report
+    async fn unlock(&self, username: &str) -> Error {
+        let request = UnlockRequest {
+            username,
+            factor_type: "EMAIL",
+        };
+
+        match self
+            .post::<_, UnlockResponse>("api/v1/authn/recovery/unlock", &request)
+            .await
+        {
+            Ok(_) => Error::Authentication(
+                "Your Okta account is locked out. Follow the link in the email Okta has sent you to unlock it, then try again"
+                    .to_string(),
+            ),
+            Err(e) => e,
+        }
+    }
+}
+
+fn state_token(state_token: Option<String>) -> Result<String, Error> {
+    state_token.ok_or_else(|| Error::Authentication("No state token found in response".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mockito::{mock, Matcher};
+    use serde_json::json;
+    use url::Url;
+
+    #[tokio::test]
+    async fn skip_password_warning() {
+        let server_url = Url::parse(&mockito::server_url()).unwrap();
+
+        let user = json!({
+            "id": "00ub0oNGTSWTBKOLGLNR",
+            "profile": {
+                "login": "isaac.brock@example.com",
+                "firstName": "Isaac",
+                "lastName": "Brock",
+                "locale": "en_US",
+                "timeZone": "America/Los_Angeles"
+            }
+        });
+
+        let login = mock("POST", "/api/v1/authn")
+            .with_body(
+                json!({
+                    "stateToken": "STATE_TOKEN",
+                    "expiresAt": "2021-07-01T12:05:00.000Z",
+                    "status": "PASSWORD_WARN",
+                    "_embedded": {
+                        "user": user,
+                        "policy": {
+                            "expiration": { "passwordExpireDays": 5 }
+                        }
+                    },
+                    "_links": {
+                        "skip": {
+                            "name": "skip",
+                            "href": format!("{}api/v1/authn/lifecycle/skip", server_url),
+                            "hints": { "allow": ["POST"] }
+                        }
+                    }
+                })
+                .to_string(),
+            )
+            .create();
+
+        let skip = mock("POST", "/api/v1/authn/lifecycle/skip")
+            .match_body(Matcher::Json(json!({ "stateToken": "STATE_TOKEN" })))
+            .with_body(
+                json!({
+                    "sessionToken": "SESSION_TOKEN",
+                    "expiresAt": "2021-07-01T12:05:00.000Z",
+                    "status": "SUCCESS",
+                    "_embedded": { "user": user }
+                })
+                .to_string(),
+            )
+            .create();
+
+        let client = Client::unauthenticated(server_url).unwrap();
+
+        let session_token = client
+            .get_session_token(&LoginRequest::from_credentials(
+                "isaac.brock@example.com".to_string(),
+                "password".to_string(),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(session_token, "SESSION_TOKEN");
+
+        login.assert();
+        skip.assert();
+    }
+
+    #[tokio::test]
+    async fn change_expired_password() {
+        let server_url = Url::parse(&mockito::server_url()).unwrap();
+
+        let change_password = mock("POST", "/api/v1/authn/credentials/change_password")
+            .match_body(Matcher::Json(json!({
+                "stateToken": "STATE_TOKEN",
+                "oldPassword": "old password",
+                "newPassword": "new password"
+            })))
+            .with_body(
+                json!({
+                    "sessionToken": "SESSION_TOKEN",
+                    "expiresAt": "2021-07-01T12:05:00.000Z",
+                    "status": "SUCCESS"
+                })
+                .to_string(),
+            )
+            .create();
+
+        let client = Client::unauthenticated(server_url).unwrap();
+
+        let response = client
+            .change_password("STATE_TOKEN", "old password", "new password".to_string())
+            .await
+            .unwrap();
+
+        assert_eq!(response.session_token.as_deref(), Some("SESSION_TOKEN"));
+        // The new password is saved instead of the one used to log in, but only once
+        assert_eq!(client.password_to_save("old password"), "new password");
+        assert_eq!(client.password_to_save("old password"), "old password");
+
+        change_password.assert();
+    }
+
+    #[tokio::test]
+    async fn reject_new_password() {
+        let server_url = Url::parse(&mockito::server_url()).unwrap();
+
+        let change_password = mock("POST", "/api/v1/authn/credentials/change_password")
+            .match_body(Matcher::PartialJson(
+                json!({ "newPassword": "too short" }),
+            ))
+            .with_status(403)
+            .with_body(
+                json!({
+                    "errorCode": "E0000080",
+                    "errorSummary": "The password does not meet the complexity requirements of the current password policy.",
+                    "errorLink": "E0000080",
+                    "errorId": "oaeS4O7BUp5Roefkk_y4Z2u8Q",
+                    "errorCauses": []
+                })
+                .to_string(),
+            )
+            .create();
+
+        let client = Client::unauthenticated(server_url).unwrap();
+
+        let err = client
+            .change_password("STATE_TOKEN", "old password", "too short".to_string())
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::Okta(ref e) if e.is_password_policy_violation()));
+        assert_eq!(client.password_to_save("old password"), "old password");
+
+        change_password.assert();
+    }
+
+    #[tokio::test]
+    async fn unlock_account() {
+        let server_url = Url::parse(&mockito::server_url()).unwrap();
+
+        let unlock = mock("POST", "/api/v1/authn/recovery/unlock")
+            .match_body(Matcher::Json(json!({
+                "username": "isaac.brock@example.com",
+                "factorType": "EMAIL"
+            })))
+            .with_body(json!({ "status": "RECOVERY_CHALLENGE" }).to_string())
+            .create();
+
+        let client = Client::unauthenticated(server_url).unwrap();
+
+        let err = client.unlock("isaac.brock@example.com").await;
+
+        assert!(matches!(err, Error::Authentication(_)));
+        assert!(err.to_string().contains("Follow the link in the email"));
+
+        unlock.assert();
+    }
+
+    #[tokio::test]
+    async fn locked_out_without_unlock() {
+        let server_url = Url::parse(&mockito::server_url()).unwrap();
+
+        let response: LoginResponse = serde_json::from_value(json!({
+            "expiresAt": "2021-07-01T12:05:00.000Z",
+            "status": "LOCKED_OUT"
+        }))
+        .unwrap();
+
+        let client = Client::unauthenticated(server_url).unwrap();
+
+        let err = client
+            .locked_out(response, Some("isaac.brock@example.com"))
+            .await;
+
+        assert!(matches!(err, Error::Authentication(_)));
+        assert!(err.to_string().contains("contact your administrator"));
+    }
+}
@@ -1,11 +1,15 @@
+use crate::cache;
+use crate::error::Error;
 use crate::okta::client::Client;
 
 use std::collections::HashSet;
 use std::fmt;
+use std::path::PathBuf;
 
-use failure::Error;
+use chrono::{DateTime, Utc};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
+use url::Url;
 
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -28,6 +32,64 @@ pub struct Session {
     pub mfa_active: bool,
 }
 
+/// The cookies identifying an Okta session and this device, kept between runs
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedSession {
+    pub session_id: String,
+    pub device_token: Option<String>,
+    pub expires_at: String,
+    /// Who the session is for, as the organization can be configured with another user or host
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub host: String,
+}
+
+impl CachedSession {
+    /// Whether this is a session for the user and host of `base_url`
+    pub fn is_for(&self, base_url: &Url) -> bool {
+        self.username == base_url.username() && Some(self.host.as_str()) == base_url.host_str()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match DateTime::parse_from_rfc3339(&self.expires_at) {
+            Ok(expires_at) => expires_at.with_timezone(&Utc) <= Utc::now(),
+            Err(_) => true,
+        }
+    }
+}
+
+/// Okta sessions by organization, so that later runs can skip logging in
+#[derive(Debug)]
+pub struct SessionCache {
+    dir: PathBuf,
+}
+
+impl SessionCache {
+    pub fn new() -> Result<SessionCache, Error> {
+        cache::dir().map(Into::into)
+    }
+
+    pub fn get(&self, organization: &str) -> Option<CachedSession> {
+        cache::read(&self.path(organization))
+    }
+
+    pub fn set(&self, organization: &str, session: &CachedSession) -> Result<(), Error> {
+        cache::write(&self.path(organization), session)
+    }
+
+    fn path(&self, organization: &str) -> PathBuf {
+        self.dir.join(format!("{}.session.json", organization))
+    }
+}
+
+impl From<PathBuf> for SessionCache {
+    fn from(dir: PathBuf) -> Self {
+        SessionCache { dir }
+    }
+}
+
 #[allow(dead_code)]
 #[derive(PartialEq, Eq, Hash)]
 pub enum SessionProperties {
@@ -101,7 +163,7 @@ impl Client {
         &mut self,
         session_token: String,
         additional_fields: &HashSet<SessionProperties>,
-    ) -> Result<(), Error> {
+    ) -> Result<Session, Error> {
         let session: Session = self
             .post(
                 &format!(
@@ -114,8 +176,78 @@ impl Client {
             )
             .await?;
 
-        self.set_session_id(session.id);
+        self.set_session_id(session.id.clone());
+
+        Ok(session)
+    }
+
+    /// The current session, if it is still active
+    pub async fn get_session(&self) -> Result<Session, Error> {
+        let session: Session = self.get("api/v1/sessions/me").await?;
+
+        match session.status {
+            SessionStatus::Active => Ok(session),
+            ref status => Err(Error::Authentication(format!(
+                "Okta session is not active ({:?})",
+                status
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Duration;
+    use tempfile;
+
+    #[test]
+    fn cache_roundtrip() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let cache = SessionCache::from(tempdir.path().to_path_buf());
+
+        assert_eq!(cache.get("mock_org"), None);
+
+        let session = CachedSession {
+            session_id: "SESSION_ID".to_string(),
+            device_token: Some("DEVICE_TOKEN".to_string()),
+            expires_at: (Utc::now() + Duration::hours(1)).to_rfc3339(),
+            username: "isaac.brock".to_string(),
+            host: "mock_org.okta.com".to_string(),
+        };
+        cache.set("mock_org", &session).unwrap();
+
+        assert!(!session.is_expired());
+        assert!(session.is_for(&"https://isaac.brock@mock_org.okta.com/".parse().unwrap()));
+        assert!(!session.is_for(&"https://other.user@mock_org.okta.com/".parse().unwrap()));
+        assert!(!session.is_for(
+            &"https://isaac.brock@mock_org.oktapreview.com/"
+                .parse()
+                .unwrap()
+        ));
+        assert_eq!(cache.get("mock_org"), Some(session));
+        assert_eq!(cache.get("other_org"), None);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let metadata = std::fs::metadata(tempdir.path().join("mock_org.session.json")).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn expired_session() {
+        let session = CachedSession {
+            session_id: "SESSION_ID".to_string(),
+            device_token: None,
+            expires_at: "2021-01-01T00:00:00.000Z".to_string(),
+            username: "isaac.brock".to_string(),
+            host: "mock_org.okta.com".to_string(),
+        };
 
-        Ok(())
+        assert!(session.is_expired());
     }
 }
@@ -1,6 +1,6 @@
+use crate::error::Error;
 use crate::okta::client::Client;
 
-use failure::Error;
 use serde::Deserialize;
 use url::Url;
 
@@ -31,11 +31,18 @@ pub struct UserProfile {
 }
 
 impl Client {
+    /// App links for the given user, or the logged-in user (which are only fetched once per run)
     pub async fn app_links(&self, user_id: Option<&str>) -> Result<Vec<AppLink>, Error> {
-        self.get(&format!(
-            "api/v1/users/{}/appLinks",
-            user_id.unwrap_or("me")
-        ))
-        .await
+        match user_id {
+            Some(user_id) => {
+                self.get(&format!("api/v1/users/{}/appLinks", user_id))
+                    .await
+            }
+            None => self
+                .app_links
+                .get_or_try_init(|| self.get("api/v1/users/me/appLinks"))
+                .await
+                .map(|app_links| app_links.to_vec()),
+        }
     }
 }
@@ -0,0 +1,21 @@
+use std::time::Duration;
+
+/// How many times a throttled request is retried before giving up
+pub const MAX_RETRIES: u32 = 5;
+
+/// How long to wait before retry number `attempt` (counting from 0), doubling each time
+pub fn backoff(attempt: u32) -> Duration {
+    Duration::from_millis(500 * 2u64.pow(attempt))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles() {
+        assert_eq!(backoff(0), Duration::from_millis(500));
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(MAX_RETRIES - 1), Duration::from_secs(8));
+    }
+}
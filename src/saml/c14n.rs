@@ -0,0 +1,217 @@
+use roxmltree::{Node, NodeId, NodeType};
+
+/// Serialize `node` as Exclusive XML Canonicalization does, leaving out `exclude` (the enveloped
+/// signature) and declaring the namespaces in `inclusive_prefixes` as inclusive canonicalization
+/// would.
+///
+/// See https://www.w3.org/TR/xml-exc-c14n/
+pub fn canonicalize(
+    node: Node,
+    exclude: Option<Node>,
+    inclusive_prefixes: &[&str],
+    with_comments: bool,
+) -> String {
+    let mut canonicalizer = Canonicalizer {
+        input: node.document().input_text(),
+        exclude: exclude.map(|node| node.id()),
+        inclusive_prefixes,
+        with_comments,
+        output: String::new(),
+    };
+
+    canonicalizer.element(node, &[]);
+    canonicalizer.output
+}
+
+struct Canonicalizer<'a, 'input> {
+    input: &'input str,
+    exclude: Option<NodeId>,
+    inclusive_prefixes: &'a [&'a str],
+    with_comments: bool,
+    output: String,
+}
+
+impl<'a, 'input> Canonicalizer<'a, 'input> {
+    /// Write an element, given the namespace declarations already written by its ancestors
+    fn element(&mut self, node: Node, rendered: &[(&str, &str)]) {
+        let name = element_name(&self.input[node.range()]);
+
+        // The namespaces used by the element and its attributes, as written in the document
+        let mut prefixes = vec![prefix(name).unwrap_or("")];
+        let mut attributes = Vec::new();
+        for attribute in node.attributes() {
+            let attribute_name = attribute_name(&self.input[attribute.range()]);
+            prefixes.extend(prefix(attribute_name));
+            attributes.push((attribute, attribute_name));
+        }
+        prefixes.extend(self.inclusive_prefixes.iter().map(|prefix| match *prefix {
+            "#default" => "",
+            prefix => prefix,
+        }));
+        prefixes.sort_unstable();
+        prefixes.dedup();
+
+        let mut rendered = rendered.to_vec();
+        let mut declarations = Vec::new();
+        for prefix in prefixes {
+            let uri = match prefix {
+                "xml" => continue,
+                "" => node.default_namespace().unwrap_or(""),
+                prefix => match node.lookup_namespace_uri(Some(prefix)) {
+                    Some(uri) => uri,
+                    None => continue,
+                },
+            };
+
+            let position = rendered.iter().position(|(p, _)| *p == prefix);
+            let changed = match position {
+                Some(position) => rendered[position].1 != uri,
+                // An element without a namespace needs no `xmlns=""` unless an ancestor has one
+                None => !uri.is_empty(),
+            };
+
+            if changed {
+                declarations.push((prefix, uri));
+                match position {
+                    Some(position) => rendered[position].1 = uri,
+                    None => rendered.push((prefix, uri)),
+                }
+            }
+        }
+
+        attributes
+            .sort_by_key(|(attribute, _)| (attribute.namespace().unwrap_or(""), attribute.name()));
+
+        self.output.push('<');
+        self.output.push_str(name);
+        for (prefix, uri) in declarations {
+            if prefix.is_empty() {
+                self.output.push_str(" xmlns=\"");
+            } else {
+                self.output.push_str(" xmlns:");
+                self.output.push_str(prefix);
+                self.output.push_str("=\"");
+            }
+            self.output.push_str(&escape_attribute(uri));
+            self.output.push('"');
+        }
+        for (attribute, attribute_name) in attributes {
+            self.output.push(' ');
+            self.output.push_str(attribute_name);
+            self.output.push_str("=\"");
+            self.output.push_str(&escape_attribute(attribute.value()));
+            self.output.push('"');
+        }
+        self.output.push('>');
+
+        for child in node.children() {
+            match child.node_type() {
+                NodeType::Element if Some(child.id()) != self.exclude => {
+                    self.element(child, &rendered)
+                }
+                NodeType::Text => self
+                    .output
+                    .push_str(&escape_text(child.text().unwrap_or(""))),
+                NodeType::Comment if self.with_comments => {
+                    self.output.push_str("<!--");
+                    self.output.push_str(child.text().unwrap_or(""));
+                    self.output.push_str("-->");
+                }
+                NodeType::PI => {
+                    if let Some(pi) = child.pi() {
+                        self.output.push_str("<?");
+                        self.output.push_str(pi.target);
+                        if let Some(value) = pi.value {
+                            self.output.push(' ');
+                            self.output.push_str(value);
+                        }
+                        self.output.push_str("?>");
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        self.output.push_str("</");
+        self.output.push_str(name);
+        self.output.push('>');
+    }
+}
+
+/// The qualified name of the element whose start tag `tag` begins with
+fn element_name(tag: &str) -> &str {
+    let tag = tag.trim_start_matches('<');
+    let end = tag
+        .find(|c: char| c.is_whitespace() || c == '/' || c == '>')
+        .unwrap_or(tag.len());
+
+    &tag[..end]
+}
+
+/// The qualified name of the attribute `attribute` (`name="value"`)
+fn attribute_name(attribute: &str) -> &str {
+    attribute.split('=').next().unwrap_or("").trim()
+}
+
+fn prefix(name: &str) -> Option<&str> {
+    name.split_once(':').map(|(prefix, _)| prefix)
+}
+
+fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('"', "&quot;")
+        .replace('\t', "&#x9;")
+        .replace('\n', "&#xA;")
+        .replace('\r', "&#xD;")
+}
+
+fn escape_text(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\r', "&#xD;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use roxmltree::Document;
+
+    fn canonical(xml: &str, element: &str, inclusive_prefixes: &[&str]) -> String {
+        let doc = Document::parse(xml).unwrap();
+        let node = doc
+            .descendants()
+            .find(|node| node.tag_name().name() == element)
+            .unwrap();
+
+        canonicalize(node, None, inclusive_prefixes, false)
+    }
+
+    #[test]
+    fn canonicalize_namespaces() {
+        let xml = r#"<a:root xmlns:a="urn:a" xmlns:b="urn:b" xmlns:c="urn:c" xmlns="urn:default"><b:child xmlns:a="urn:a" z="1" c:y="2" a:x="3"><a:inner/><plain/></b:child></a:root>"#;
+
+        // Only the namespaces each element uses are declared, on the highest element using them
+        assert_eq!(
+            canonical(xml, "child", &[]),
+            r#"<b:child xmlns:a="urn:a" xmlns:b="urn:b" xmlns:c="urn:c" z="1" a:x="3" c:y="2"><a:inner></a:inner><plain xmlns="urn:default"></plain></b:child>"#
+        );
+        assert_eq!(
+            canonical(xml, "inner", &["b", "#default", "missing"]),
+            r#"<a:inner xmlns="urn:default" xmlns:a="urn:a" xmlns:b="urn:b"></a:inner>"#
+        );
+    }
+
+    #[test]
+    fn canonicalize_text() {
+        let xml = "<root>\r\n  <child attr='a&amp;b \"c\"&#9;'>1 &lt; 2 &amp;&amp; 3 &gt; 2<!-- comment --><![CDATA[<data>]]></child>\n</root>";
+
+        assert_eq!(
+            canonical(xml, "root", &[]),
+            "<root>\n  <child attr=\"a&amp;b &quot;c&quot;&#x9;\">1 &lt; 2 &amp;&amp; 3 &gt; 2&lt;data&gt;</child>\n</root>"
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use crate::error::Error;
+
+use chrono::{DateTime, Duration, Utc};
+use roxmltree::{Document, Node};
+use url::Url;
+
+const SAML_ASSERTION: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+
+/// How far the identity provider's clock can be from ours
+const CLOCK_SKEW_SECONDS: i64 = 300;
+
+/// The hosts of the AWS sign-in endpoints, which are also accepted as audiences
+const AWS_SIGNIN_HOSTS: &[&str] = &[
+    "signin.aws.amazon.com",
+    "signin.amazonaws-us-gov.com",
+    "signin.amazonaws.cn",
+];
+
+/// Check that the assertions in a (decrypted) SAML response are meant for AWS, and valid at `now`
+pub fn check(xml: &str, now: DateTime<Utc>) -> Result<(), Error> {
+    let doc = Document::parse(xml).map_err(|e| Error::Saml(e.to_string()))?;
+    let skew = Duration::seconds(CLOCK_SKEW_SECONDS);
+
+    for assertion in doc
+        .descendants()
+        .filter(|node| node.has_tag_name((SAML_ASSERTION, "Assertion")))
+    {
+        if let Some(conditions) = child(assertion, "Conditions") {
+            if let Some(not_before) = time(conditions, "NotBefore")? {
+                if now + skew < not_before {
+                    return Err(Error::Saml(format!(
+                        "The SAML assertion is not valid until {}, check that your clock is correct",
+                        not_before
+                    )));
+                }
+            }
+
+            check_expiry(conditions, now - skew)?;
+
+            for restriction in conditions
+                .children()
+                .filter(|node| node.has_tag_name((SAML_ASSERTION, "AudienceRestriction")))
+            {
+                let audiences = restriction
+                    .children()
+                    .filter(|node| node.has_tag_name((SAML_ASSERTION, "Audience")))
+                    .map(|node| node.text().unwrap_or("").trim())
+                    .collect::<Vec<_>>();
+
+                if !audiences.iter().any(|audience| is_aws_audience(audience)) {
+                    return Err(Error::Saml(format!(
+                        "The SAML assertion is for {}, not AWS",
+                        audiences.join(", ")
+                    )));
+                }
+            }
+        }
+
+        for confirmation_data in assertion
+            .descendants()
+            .filter(|node| node.has_tag_name((SAML_ASSERTION, "SubjectConfirmationData")))
+        {
+            check_expiry(confirmation_data, now - skew)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn check_expiry(node: Node, now: DateTime<Utc>) -> Result<(), Error> {
+    match time(node, "NotOnOrAfter")? {
+        Some(not_on_or_after) if now >= not_on_or_after => Err(Error::Saml(format!(
+            "The SAML assertion expired at {}",
+            not_on_or_after
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|child| child.has_tag_name((SAML_ASSERTION, name)))
+}
+
+fn time(node: Node, attribute: &str) -> Result<Option<DateTime<Utc>>, Error> {
+    node.attribute(attribute)
+        .map(|time| {
+            DateTime::parse_from_rfc3339(time)
+                .map(|time| time.with_timezone(&Utc))
+                .map_err(|_| {
+                    Error::Saml(format!(
+                        "Invalid {} time {} in SAML assertion",
+                        attribute, time
+                    ))
+                })
+        })
+        .transpose()
+}
+
+/// Whether `audience` is AWS: `urn:amazon:webservices` (or a partition's variant of it), or one
+/// of the sign-in endpoints
+fn is_aws_audience(audience: &str) -> bool {
+    if audience.starts_with("urn:amazon:webservices") {
+        return true;
+    }
+
+    match Url::parse(audience) {
+        Ok(url) => match url.host_str() {
+            Some(host) if url.path() == "/saml" => AWS_SIGNIN_HOSTS
+                .iter()
+                .any(|signin| host == *signin || host.ends_with(&format!(".{}", signin))),
+            _ => false,
+        },
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::read_to_string;
+
+    fn signed_response() -> String {
+        read_to_string("tests/fixtures/saml_response_signed.xml").unwrap()
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn check_times() {
+        let xml = signed_response();
+
+        assert!(check(&xml, at("2021-06-10T18:24:52Z")).is_ok());
+        // Within the allowed clock skew of the conditions
+        assert!(check(&xml, at("2021-06-10T18:15:00Z")).is_ok());
+        assert!(check(&xml, at("2099-01-01T00:04:59Z")).is_ok());
+
+        assert_eq!(
+            check(&xml, at("2021-06-10T18:14:00Z"))
+                .unwrap_err()
+                .to_string(),
+            "The SAML assertion is not valid until 2021-06-10 18:19:52.123 UTC, check that your clock is correct"
+        );
+        assert_eq!(
+            check(&xml, at("2099-01-01T00:05:00Z"))
+                .unwrap_err()
+                .to_string(),
+            "The SAML assertion expired at 2099-01-01 00:00:00 UTC"
+        );
+    }
+
+    #[test]
+    fn check_subject_confirmation_expiry() {
+        let xml = signed_response().replace(
+            r#"NotOnOrAfter="2099-01-01T00:05:00.000Z""#,
+            r#"NotOnOrAfter="2021-06-10T18:29:52.123Z""#,
+        );
+
+        assert!(check(&xml, at("2021-06-10T18:30:00Z")).is_ok());
+        assert!(check(&xml, at("2021-06-10T18:35:00Z")).is_err());
+    }
+
+    #[test]
+    fn check_audiences() {
+        let now = at("2021-06-10T18:24:52Z");
+
+        // Not an AWS audience
+        let xml = read_to_string("tests/fixtures/saml_response.xml").unwrap();
+        assert_eq!(
+            check(&xml, at("2014-07-17T01:01:48Z"))
+                .unwrap_err()
+                .to_string(),
+            "The SAML assertion is for http://sp.example.com/demo1/metadata.php, not AWS"
+        );
+
+        for audience in &[
+            "urn:amazon:webservices:govcloud",
+            "https://signin.aws.amazon.com/saml",
+            "https://us-east-1.signin.aws.amazon.com/saml",
+        ] {
+            let xml = signed_response().replace("urn:amazon:webservices", audience);
+            assert!(check(&xml, now).is_ok(), "{}", audience);
+        }
+
+        for audience in &[
+            "https://signin.aws.amazon.com.example.com/saml",
+            "https://example.com/saml",
+        ] {
+            let xml = signed_response().replace("urn:amazon:webservices", audience);
+            assert!(check(&xml, now).is_err(), "{}", audience);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use crate::error::Error;
+
+use std::fs::read_to_string;
+use std::path::Path;
+
+use aes::{Aes128, Aes192, Aes256};
+use aes_gcm::aead::generic_array::typenum::{Unsigned, U12};
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes128Gcm, Aes256Gcm, AesGcm};
+use block_modes::block_padding::NoPadding;
+use block_modes::cipher::{BlockCipher, NewBlockCipher};
+use block_modes::{BlockMode, Cbc};
+use roxmltree::{Document, Node};
+use rsa::pkcs1::FromRsaPrivateKey;
+use rsa::pkcs8::FromPrivateKey;
+use rsa::{PaddingScheme, RsaPrivateKey};
+use sha1::Sha1;
+use sha2::Sha256;
+
+// https://www.w3.org/TR/xmlenc-core1/
+const AES128_CBC: &str = "http://www.w3.org/2001/04/xmlenc#aes128-cbc";
+const AES192_CBC: &str = "http://www.w3.org/2001/04/xmlenc#aes192-cbc";
+const AES256_CBC: &str = "http://www.w3.org/2001/04/xmlenc#aes256-cbc";
+const AES128_GCM: &str = "http://www.w3.org/2009/xmlenc11#aes128-gcm";
+const AES192_GCM: &str = "http://www.w3.org/2009/xmlenc11#aes192-gcm";
+const AES256_GCM: &str = "http://www.w3.org/2009/xmlenc11#aes256-gcm";
+const RSA_OAEP_MGF1P: &str = "http://www.w3.org/2001/04/xmlenc#rsa-oaep-mgf1p";
+const RSA_OAEP: &str = "http://www.w3.org/2009/xmlenc11#rsa-oaep";
+const SHA1: &str = "http://www.w3.org/2000/09/xmldsig#sha1";
+const SHA256: &str = "http://www.w3.org/2001/04/xmlenc#sha256";
+const MGF1_SHA1: &str = "http://www.w3.org/2009/xmlenc11#mgf1sha1";
+const MGF1_SHA256: &str = "http://www.w3.org/2009/xmlenc11#mgf1sha256";
+
+/// The SAML elements that can be encrypted and hold something oktaws needs
+const ENCRYPTED_ELEMENTS: &[&str] = &["EncryptedAssertion", "EncryptedAttribute"];
+
+const AES_BLOCK_LEN: usize = 16;
+const GCM_NONCE_LEN: usize = 12;
+const GCM_TAG_LEN: usize = 16;
+
+/// Replace the encrypted assertions and attributes in a SAML response with their decrypted XML,
+/// using the private key at `private_key`
+pub fn decrypt(mut xml: String, private_key: Option<&Path>) -> Result<String, Error> {
+    let mut key = None;
+
+    // A decrypted assertion can hold encrypted attributes, so repeat until none are left
+    loop {
+        let decrypted = {
+            let doc = Document::parse(&xml).map_err(|e| Error::Saml(e.to_string()))?;
+
+            match doc
+                .descendants()
+                .find(|node| ENCRYPTED_ELEMENTS.contains(&node.tag_name().name()))
+            {
+                Some(element) => {
+                    let key = match key {
+                        Some(ref key) => key,
+                        None => key.get_or_insert(load_private_key(private_key)?),
+                    };
+
+                    Some((element.range(), decrypt_element(element, key)?))
+                }
+                None => None,
+            }
+        };
+
+        match decrypted {
+            Some((range, plaintext)) => xml.replace_range(range, &plaintext),
+            None => return Ok(xml),
+        }
+    }
+}
+
+fn load_private_key(path: Option<&Path>) -> Result<RsaPrivateKey, Error> {
+    let path = path.ok_or_else(|| {
+        Error::Saml(
+            "The SAML assertion is encrypted, set `saml_private_key` for the organization to decrypt it"
+                .to_string(),
+        )
+    })?;
+
+    let pem = read_to_string(path).map_err(|e| {
+        Error::Config(format!(
+            "Could not read SAML private key from {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+
+    RsaPrivateKey::from_pkcs8_pem(&pem)
+        .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+        .map_err(|e| {
+            Error::Config(format!(
+                "Invalid SAML private key in {}: {}",
+                path.display(),
+                e
+            ))
+        })
+}
+
+/// The XML of an `EncryptedAssertion` or `EncryptedAttribute` element
+fn decrypt_element(element: Node, private_key: &RsaPrivateKey) -> Result<String, Error> {
+    let encrypted_data = child(element, "EncryptedData")?;
+
+    // The key is usually within the data, but can also be alongside it
+    let encrypted_key = child(encrypted_data, "KeyInfo")
+        .and_then(|key_info| child(key_info, "EncryptedKey"))
+        .or_else(|_| child(element, "EncryptedKey"))?;
+
+    let key = private_key
+        .decrypt(oaep_padding(encrypted_key)?, &cipher_value(encrypted_key)?)
+        .map_err(|e| Error::Saml(format!("Could not decrypt SAML assertion key: {}", e)))?;
+
+    let data = cipher_value(encrypted_data)?;
+    let plaintext = match encryption_method(encrypted_data)? {
+        AES128_CBC => decrypt_cbc::<Aes128>(&key, &data),
+        AES192_CBC => decrypt_cbc::<Aes192>(&key, &data),
+        AES256_CBC => decrypt_cbc::<Aes256>(&key, &data),
+        AES128_GCM => decrypt_gcm::<Aes128Gcm>(&key, &data),
+        AES192_GCM => decrypt_gcm::<AesGcm<Aes192, U12>>(&key, &data),
+        AES256_GCM => decrypt_gcm::<Aes256Gcm>(&key, &data),
+        algorithm => Err(Error::Saml(format!(
+            "Unsupported SAML encryption algorithm {}",
+            algorithm
+        ))),
+    }?;
+
+    String::from_utf8(plaintext).map_err(Into::into)
+}
+
+/// The RSA-OAEP parameters used to encrypt the key
+fn oaep_padding(encrypted_key: Node) -> Result<PaddingScheme, Error> {
+    let method = child(encrypted_key, "EncryptionMethod")?;
+    let parameter = |name| {
+        child(method, name)
+            .ok()
+            .and_then(|node| node.attribute("Algorithm"))
+    };
+
+    let digest = parameter("DigestMethod").unwrap_or(SHA1);
+    let mgf = match encryption_method(encrypted_key)? {
+        // The original RSA-OAEP always uses MGF1 with SHA-1
+        RSA_OAEP_MGF1P => MGF1_SHA1,
+        RSA_OAEP => parameter("MGF").unwrap_or(MGF1_SHA1),
+        algorithm => {
+            return Err(Error::Saml(format!(
+                "Unsupported SAML key encryption algorithm {}",
+                algorithm
+            )))
+        }
+    };
+
+    match (digest, mgf) {
+        (SHA1, MGF1_SHA1) => Ok(PaddingScheme::new_oaep::<Sha1>()),
+        (SHA1, MGF1_SHA256) => Ok(PaddingScheme::new_oaep_with_mgf_hash::<Sha1, Sha256>()),
+        (SHA256, MGF1_SHA1) => Ok(PaddingScheme::new_oaep_with_mgf_hash::<Sha256, Sha1>()),
+        (SHA256, MGF1_SHA256) => Ok(PaddingScheme::new_oaep::<Sha256>()),
+        _ => Err(Error::Saml(format!(
+            "Unsupported RSA-OAEP parameters ({}, {})",
+            digest, mgf
+        ))),
+    }
+}
+
+/// AES-CBC, with the IV before the ciphertext
+fn decrypt_cbc<C: BlockCipher + NewBlockCipher>(key: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::Saml("Could not decrypt SAML assertion".to_string());
+
+    if data.len() < AES_BLOCK_LEN {
+        return Err(invalid());
+    }
+    let (iv, ciphertext) = data.split_at(AES_BLOCK_LEN);
+
+    let mut plaintext = Cbc::<C, NoPadding>::new_var(key, iv)
+        .map_err(|_| invalid())?
+        .decrypt_vec(ciphertext)
+        .map_err(|_| invalid())?;
+
+    // XML Encryption padding only requires the last byte to be the length of the padding
+    let padding = *plaintext.last().ok_or_else(invalid)? as usize;
+    if padding == 0 || padding > AES_BLOCK_LEN || padding > plaintext.len() {
+        return Err(invalid());
+    }
+    plaintext.truncate(plaintext.len() - padding);
+
+    Ok(plaintext)
+}
+
+/// AES-GCM, with the nonce before the ciphertext and the tag after it
+fn decrypt_gcm<A: NewAead + Aead<NonceSize = U12>>(
+    key: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::Saml("Could not decrypt SAML assertion".to_string());
+
+    if key.len() != A::KeySize::USIZE || data.len() < GCM_NONCE_LEN + GCM_TAG_LEN {
+        return Err(invalid());
+    }
+    let (nonce, ciphertext) = data.split_at(GCM_NONCE_LEN);
+
+    A::new(GenericArray::from_slice(key))
+        .decrypt(GenericArray::from_slice(nonce), ciphertext)
+        .map_err(|_| invalid())
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Result<Node<'a, 'input>, Error> {
+    node.children()
+        .find(|child| child.tag_name().name() == name)
+        .ok_or_else(|| Error::Saml(format!("No {} found in {}", name, node.tag_name().name())))
+}
+
+fn encryption_method<'a>(node: Node<'a, '_>) -> Result<&'a str, Error> {
+    child(node, "EncryptionMethod")?
+        .attribute("Algorithm")
+        .ok_or_else(|| Error::Saml("No encryption algorithm found".to_string()))
+}
+
+fn cipher_value(node: Node) -> Result<Vec<u8>, Error> {
+    let value = child(child(node, "CipherData")?, "CipherValue")?
+        .text()
+        .unwrap_or_default();
+
+    // The base64 value is often wrapped over several lines
+    let value: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+
+    base64::decode(value).map_err(Into::into)
+}
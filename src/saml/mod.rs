@@ -0,0 +1,321 @@
+mod c14n;
+mod conditions;
+mod encryption;
+pub mod signature;
+
+use crate::aws::role::Role;
+use crate::error::Error;
+use crate::saml::signature::Certificate;
+
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::path::Path;
+
+use chrono::Utc;
+use roxmltree::{Document, Node};
+
+const SAML_ASSERTION: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+const ROLE_ATTRIBUTE: &str = "https://aws.amazon.com/SAML/Attributes/Role";
+const SESSION_DURATION_ATTRIBUTE: &str = "https://aws.amazon.com/SAML/Attributes/SessionDuration";
+
+#[derive(Debug)]
+pub struct Response {
+    pub raw: String,
+    pub roles: HashSet<Role>,
+    /// The longest session the identity provider allows, in seconds
+    pub session_duration: Option<i64>,
+}
+
+impl TryFrom<String> for Response {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Response::new(s, None)
+    }
+}
+
+impl Response {
+    /// Parse a base64-encoded SAML response, decrypting its assertions with `private_key` if they
+    /// are encrypted. `raw` is kept as it is, for STS to decrypt itself.
+    pub fn new(s: String, private_key: Option<&Path>) -> Result<Self, Error> {
+        let decoded_saml = decode(&s)?;
+        let decoded_saml = encryption::decrypt(decoded_saml, private_key)?;
+
+        Response::from_xml(s, &decoded_saml)
+    }
+
+    /// Parse a base64-encoded SAML response like `new`, after checking that it is signed with
+    /// `certificate` (if there is one), and that its assertion is meant for AWS and still valid
+    pub fn validated(
+        s: String,
+        private_key: Option<&Path>,
+        certificate: Option<&Certificate>,
+    ) -> Result<Self, Error> {
+        let decoded_saml = decode(&s)?;
+
+        // Only what the signature covers is used, so nothing can be slipped in next to it. The
+        // signature can cover the encrypted assertion, or be inside it.
+        let decoded_saml = match certificate {
+            Some(certificate) => match signature::verify(&decoded_saml, certificate)? {
+                Some(signed) => encryption::decrypt(signed, private_key)?,
+                None => {
+                    let decrypted = encryption::decrypt(decoded_saml, private_key)?;
+
+                    signature::verify(&decrypted, certificate)?
+                        .ok_or_else(|| Error::Saml("The SAML response is not signed".to_string()))?
+                }
+            },
+            None => encryption::decrypt(decoded_saml, private_key)?,
+        };
+
+        conditions::check(&decoded_saml, Utc::now())?;
+
+        Response::from_xml(s, &decoded_saml)
+    }
+
+    /// Read the roles and session duration from the decoded and decrypted SAML response, or from
+    /// its assertion when only that is signed
+    fn from_xml(raw: String, decoded_saml: &str) -> Result<Self, Error> {
+        let doc = Document::parse(decoded_saml).map_err(|e| Error::Saml(e.to_string()))?;
+        let root = doc.root_element();
+
+        let assertions = if root.has_tag_name((SAML_ASSERTION, "Assertion")) {
+            vec![root]
+        } else {
+            if root
+                .children()
+                .any(|node| node.has_tag_name((SAML_ASSERTION, "EncryptedAssertion")))
+            {
+                return Err(Error::Saml(
+                    "SAML assertions are still encrypted after decryption".to_string(),
+                ));
+            }
+
+            root.children()
+                .filter(|node| node.has_tag_name((SAML_ASSERTION, "Assertion")))
+                .collect()
+        };
+
+        if assertions.is_empty() {
+            return Err(Error::Saml("No roles found".to_string()));
+        }
+
+        let statements = assertions
+            .into_iter()
+            .flat_map(|assertion| children(assertion, "AttributeStatement"))
+            .collect::<Vec<_>>();
+
+        if statements
+            .iter()
+            .any(|statement| children(*statement, "EncryptedAttribute").next().is_some())
+        {
+            return Err(Error::Saml(
+                "SAML attributes are still encrypted after decryption".to_string(),
+            ));
+        }
+
+        let attributes = statements
+            .into_iter()
+            .flat_map(|statement| children(statement, "Attribute"))
+            .collect::<Vec<_>>();
+
+        let session_duration = attribute_values(&attributes, SESSION_DURATION_ATTRIBUTE)
+            .and_then(|values| values.first().copied())
+            .map(|duration| {
+                duration.trim().parse::<i64>().map_err(|_| {
+                    Error::Saml(format!("Invalid SessionDuration attribute {}", duration))
+                })
+            })
+            .transpose()?;
+
+        if let Some(role_values) = attribute_values(&attributes, ROLE_ATTRIBUTE) {
+            Ok(Response {
+                raw,
+                roles: role_values
+                    .into_iter()
+                    .map(|arn| arn.parse())
+                    .collect::<Result<HashSet<Role>, Error>>()?,
+                session_duration,
+            })
+        } else {
+            Err(Error::Saml("No Role Attributes found".to_string()))
+        }
+    }
+}
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children()
+        .filter(move |child| child.has_tag_name((SAML_ASSERTION, name)))
+}
+
+/// The values of the first attribute named `name`
+fn attribute_values<'a>(attributes: &[Node<'a, '_>], name: &str) -> Option<Vec<&'a str>> {
+    attributes
+        .iter()
+        .find(|attribute| attribute.attribute("Name") == Some(name))
+        .map(|attribute| {
+            children(*attribute, "AttributeValue")
+                .map(|value| value.text().unwrap_or(""))
+                .collect()
+        })
+}
+
+fn decode(s: &str) -> Result<String, Error> {
+    let decoded_saml = String::from_utf8(base64::decode(s)?)?;
+
+    trace!("Decoded SAML: {}", decoded_saml);
+
+    Ok(decoded_saml)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::{read_to_string, File};
+    use std::io::Read;
+
+    use base64::encode;
+
+    #[test]
+    fn parse_response_invalid_no_role() {
+        let mut f =
+            File::open("tests/fixtures/saml_response_invalid_no_role.xml").expect("file not found");
+
+        let mut saml_xml = String::new();
+        f.read_to_string(&mut saml_xml)
+            .expect("something went wrong reading the file");
+
+        let saml_base64 = encode(&saml_xml);
+
+        let response: Error = Response::try_from(saml_base64).unwrap_err();
+
+        assert!(matches!(response, Error::Saml(_)));
+        assert_eq!(
+            response.to_string(),
+            "Not enough elements in arn:aws:iam::123456789012:saml-provider/okta-idp"
+        );
+    }
+
+    fn read_fixture(path: &str) -> String {
+        let mut saml_xml = String::new();
+        File::open(path)
+            .expect("file not found")
+            .read_to_string(&mut saml_xml)
+            .expect("something went wrong reading the file");
+
+        encode(&saml_xml)
+    }
+
+    #[test]
+    fn parse_encrypted_responses() {
+        let private_key = Path::new("tests/fixtures/saml_private_key.pem");
+        let expected = Response::try_from(read_fixture("tests/fixtures/saml_response.xml"))
+            .unwrap()
+            .roles;
+
+        for path in &[
+            "tests/fixtures/saml_response_encrypted_assertion.xml",
+            "tests/fixtures/saml_response_encrypted_attribute.xml",
+        ] {
+            let saml_base64 = read_fixture(path);
+            let response = Response::new(saml_base64.clone(), Some(private_key)).unwrap();
+
+            assert_eq!(response.roles, expected, "{}", path);
+            assert_eq!(response.session_duration, Some(43200));
+            // STS is given the response as Okta sent it
+            assert_eq!(response.raw, saml_base64);
+        }
+    }
+
+    #[test]
+    fn encrypted_response_without_key() {
+        let saml_base64 = read_fixture("tests/fixtures/saml_response_encrypted_assertion.xml");
+
+        let error = Response::try_from(saml_base64).unwrap_err();
+
+        assert!(matches!(error, Error::Saml(_)));
+        assert!(error.to_string().contains("saml_private_key"));
+    }
+
+    #[test]
+    fn still_encrypted_attribute() {
+        let xml = read_to_string("tests/fixtures/saml_response_encrypted_attribute.xml").unwrap();
+
+        let error = Response::from_xml(String::new(), &xml).unwrap_err();
+
+        assert!(matches!(error, Error::Saml(_)));
+    }
+
+    #[test]
+    fn validate_signed_response() {
+        let certificate =
+            Certificate::from_file(Path::new("tests/fixtures/saml_certificate.pem")).unwrap();
+        let saml_base64 = read_fixture("tests/fixtures/saml_response_signed.xml");
+
+        let response = Response::validated(saml_base64, None, Some(&certificate)).unwrap();
+
+        assert_eq!(response.roles.len(), 2);
+        assert_eq!(response.session_duration, Some(43200));
+    }
+
+    #[test]
+    fn validate_without_certificate() {
+        // Signatures are only checked with a certificate, but times and audiences always are
+        let saml_base64 = read_fixture("tests/fixtures/saml_response_signed.xml");
+        let response = Response::validated(saml_base64, None, None).unwrap();
+        assert_eq!(response.roles.len(), 2);
+
+        let saml_base64 = read_fixture("tests/fixtures/saml_response.xml");
+        let error = Response::validated(saml_base64, None, None).unwrap_err();
+        assert!(matches!(error, Error::Saml(_)));
+    }
+
+    #[test]
+    fn validate_signed_assertion() {
+        let certificate =
+            Certificate::from_file(Path::new("tests/fixtures/saml_certificate.pem")).unwrap();
+        let xml = read_to_string("tests/fixtures/saml_response_signed.xml").unwrap();
+
+        // Only the assertion is signed, so a role added to the response outside it is not used
+        let start = xml.find("<ds:Signature").unwrap();
+        let end = xml.find("</ds:Signature>").unwrap() + "</ds:Signature>".len();
+        let xml = format!("{}{}", &xml[..start], &xml[end..]).replace(
+            "<saml2p:Status ",
+            r#"<saml2:AttributeStatement xmlns:saml2="urn:oasis:names:tc:SAML:2.0:assertion"><saml2:Attribute Name="https://aws.amazon.com/SAML/Attributes/Role"><saml2:AttributeValue>arn:aws:iam::123456789012:saml-provider/okta-idp,arn:aws:iam::123456789012:role/admin</saml2:AttributeValue></saml2:Attribute></saml2:AttributeStatement><saml2p:Status "#,
+        );
+
+        let response = Response::validated(encode(&xml), None, Some(&certificate)).unwrap();
+
+        let mut roles = response
+            .roles
+            .iter()
+            .map(|role| role.role_arn.as_str())
+            .collect::<Vec<_>>();
+        roles.sort_unstable();
+        assert_eq!(
+            roles,
+            vec![
+                "arn:aws:iam::123456789012:role/role1",
+                "arn:aws:iam::123456789012:role/role2"
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_response_signed_by_others() {
+        let certificate =
+            Certificate::from_file(Path::new("tests/fixtures/saml_certificate.pem")).unwrap();
+        let saml_base64 = read_fixture("tests/fixtures/saml_response_encrypted_assertion.xml");
+        let private_key = Path::new("tests/fixtures/saml_private_key.pem");
+
+        // Signed, but not with this certificate
+        let error =
+            Response::validated(saml_base64, Some(private_key), Some(&certificate)).unwrap_err();
+
+        assert!(matches!(error, Error::Saml(_)));
+    }
+}
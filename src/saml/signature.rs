@@ -0,0 +1,520 @@
+use crate::error::Error;
+use crate::saml::c14n::canonicalize;
+
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use roxmltree::{Document, Node};
+use rsa::pkcs8::FromPublicKey;
+use rsa::{Hash, PaddingScheme, PublicKey, RsaPublicKey};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use url::Url;
+
+// https://www.w3.org/TR/xmldsig-core1/
+const DSIG: &str = "http://www.w3.org/2000/09/xmldsig#";
+const EXC_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const EXC_C14N_WITH_COMMENTS: &str = "http://www.w3.org/2001/10/xml-exc-c14n#WithComments";
+const ENVELOPED_SIGNATURE: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+const RSA_SHA1: &str = "http://www.w3.org/2000/09/xmldsig#rsa-sha1";
+const RSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+const SHA1: &str = "http://www.w3.org/2000/09/xmldsig#sha1";
+const SHA256: &str = "http://www.w3.org/2001/04/xmlenc#sha256";
+
+const SAML_ASSERTION: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+const SAML_METADATA: &str = "urn:oasis:names:tc:SAML:2.0:metadata";
+
+/// Where to get the certificate the identity provider signs SAML responses with
+#[derive(Clone, Debug, PartialEq)]
+pub enum SamlCertificate {
+    File(PathBuf),
+    Metadata(Url),
+}
+
+impl SamlCertificate {
+    /// Parse a `saml_certificate` setting: the HTTPS URL of the identity provider's metadata, or
+    /// the path of a PEM-encoded certificate, relative to `dir`
+    pub fn from_config(value: &str, dir: Option<&Path>) -> Result<Self, Error> {
+        match Url::parse(value) {
+            Ok(url) if url.scheme() == "https" => Ok(SamlCertificate::Metadata(url)),
+            // Anyone between here and the identity provider could replace the certificate
+            Ok(url) if url.scheme() == "http" => Err(Error::Config(format!(
+                "The SAML metadata must be fetched over HTTPS, not from {}",
+                url
+            ))),
+            _ => Ok(SamlCertificate::File(match dir {
+                Some(dir) => dir.join(value),
+                None => PathBuf::from(value),
+            })),
+        }
+    }
+}
+
+/// The public key of an identity provider's signing certificate
+#[derive(Clone, Debug, PartialEq)]
+pub struct Certificate(RsaPublicKey);
+
+impl Certificate {
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let pem = read_to_string(path).map_err(|e| {
+            Error::Config(format!(
+                "Could not read the SAML certificate from {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+
+        Certificate::from_pem(&pem)
+    }
+
+    /// Read the first certificate in a PEM file
+    pub fn from_pem(pem: &str) -> Result<Self, Error> {
+        let invalid = || Error::Config("The SAML certificate is not a PEM certificate".to_string());
+
+        let (_, rest) = pem
+            .split_once("-----BEGIN CERTIFICATE-----")
+            .ok_or_else(invalid)?;
+        let (encoded, _) = rest
+            .split_once("-----END CERTIFICATE-----")
+            .ok_or_else(invalid)?;
+
+        Certificate::from_base64(encoded)
+    }
+
+    /// Read the signing certificate from the identity provider's SAML metadata
+    pub fn from_metadata(xml: &str) -> Result<Self, Error> {
+        let doc = Document::parse(xml)
+            .map_err(|e| Error::Config(format!("Invalid SAML metadata: {}", e)))?;
+
+        let certificate = doc
+            .descendants()
+            .filter(|node| node.has_tag_name((SAML_METADATA, "KeyDescriptor")))
+            .filter(|node| node.attribute("use").unwrap_or("signing") == "signing")
+            .flat_map(|node| node.descendants())
+            .find(|node| node.has_tag_name((DSIG, "X509Certificate")))
+            .and_then(|node| node.text())
+            .ok_or_else(|| {
+                Error::Config("No signing certificate found in the SAML metadata".to_string())
+            })?;
+
+        Certificate::from_base64(certificate)
+    }
+
+    fn from_base64(encoded: &str) -> Result<Self, Error> {
+        let der = base64::decode(strip_whitespace(encoded))
+            .map_err(|e| Error::Config(format!("Invalid SAML certificate: {}", e)))?;
+
+        Certificate::from_der(&der)
+    }
+
+    /// Read the RSA public key from a DER-encoded X.509 certificate
+    fn from_der(der: &[u8]) -> Result<Self, Error> {
+        let invalid = || Error::Config("Invalid SAML certificate".to_string());
+
+        // Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signatureValue }
+        let (_, certificate, _) = der_element(der).ok_or_else(invalid)?;
+        let (_, mut tbs_certificate, _) = der_element(certificate).ok_or_else(invalid)?;
+
+        // The optional [0] version, then serialNumber, signature, issuer, validity and subject
+        if tbs_certificate.first() == Some(&0xa0) {
+            tbs_certificate = der_element(tbs_certificate).ok_or_else(invalid)?.2;
+        }
+        for _ in 0..5 {
+            tbs_certificate = der_element(tbs_certificate).ok_or_else(invalid)?.2;
+        }
+
+        let (public_key_len, _, _) = der_element(tbs_certificate).ok_or_else(invalid)?;
+
+        RsaPublicKey::from_public_key_der(&tbs_certificate[..public_key_len])
+            .map(Certificate)
+            .map_err(|e| Error::Config(format!("The SAML certificate has no RSA key: {}", e)))
+    }
+}
+
+/// Split the first DER element from `der`, returning its length, its contents, and what follows
+fn der_element(der: &[u8]) -> Option<(usize, &[u8], &[u8])> {
+    let first = *der.get(1)? as usize;
+    let (header_len, contents_len) = if first < 0x80 {
+        (2, first)
+    } else {
+        let len_len = first & 0x7f;
+        if len_len == 0 || len_len > 4 {
+            return None;
+        }
+
+        let len = der
+            .get(2..2 + len_len)?
+            .iter()
+            .fold(0, |len, byte| (len << 8) | *byte as usize);
+        (2 + len_len, len)
+    };
+
+    let len = header_len + contents_len;
+
+    Some((len, der.get(header_len..len)?, &der[len..]))
+}
+
+/// The canonical XML of the signed SAML response or assertion, if either is signed, failing if it
+/// is signed with a key other than `certificate`'s or the signed element has been changed.
+///
+/// Only the returned XML is covered by the signature, so it is what must be used, not `xml`.
+pub fn verify(xml: &str, certificate: &Certificate) -> Result<Option<String>, Error> {
+    let doc = Document::parse(xml).map_err(|e| Error::Saml(e.to_string()))?;
+    let response = doc.root_element();
+
+    // Another assertion could be slipped in next to a signed one, so only accept one
+    let assertions = response
+        .descendants()
+        .filter(|node| {
+            node.has_tag_name((SAML_ASSERTION, "Assertion"))
+                || node.has_tag_name((SAML_ASSERTION, "EncryptedAssertion"))
+        })
+        .collect::<Vec<_>>();
+
+    let assertion = match assertions[..] {
+        [assertion] => assertion,
+        _ => {
+            return Err(Error::Saml(format!(
+                "Expected one SAML assertion, found {}",
+                assertions.len()
+            )))
+        }
+    };
+
+    if assertion.parent_element() != Some(response) {
+        return Err(Error::Saml(
+            "The SAML assertion is not part of the response".to_string(),
+        ));
+    }
+
+    // Signatures are only expected on the response and assertion, once each
+    let signatures = doc
+        .descendants()
+        .filter(|node| node.has_tag_name((DSIG, "Signature")))
+        .collect::<Vec<_>>();
+    if signatures.iter().any(|signature| {
+        let parent = signature.parent_element();
+
+        (parent != Some(response) && parent != Some(assertion))
+            || signatures
+                .iter()
+                .filter(|other| other.parent_element() == parent)
+                .count()
+                > 1
+    }) {
+        return Err(Error::Saml(
+            "Unexpected signatures found in the SAML response".to_string(),
+        ));
+    }
+
+    // A signed response covers the assertion, so it is enough for either to be signed
+    for element in &[response, assertion] {
+        if let Some(signature) = element
+            .children()
+            .find(|node| node.has_tag_name((DSIG, "Signature")))
+        {
+            return verify_signature(*element, signature, certificate).map(Some);
+        }
+    }
+
+    Ok(None)
+}
+
+/// Check the enveloped `signature` of `element`, returning the canonical XML that was signed
+fn verify_signature(
+    element: Node,
+    signature: Node,
+    certificate: &Certificate,
+) -> Result<String, Error> {
+    let signed_info = child(signature, "SignedInfo")?;
+
+    let id = element
+        .attribute("ID")
+        .ok_or_else(|| Error::Saml("The signed SAML element has no ID".to_string()))?;
+
+    // With duplicate IDs, the signed element might not be the one that is used
+    if element
+        .document()
+        .descendants()
+        .filter(|node| node.attribute("ID") == Some(id))
+        .count()
+        > 1
+    {
+        return Err(Error::Saml(format!(
+            "Several SAML elements have the ID {}",
+            id
+        )));
+    }
+
+    let references = signed_info
+        .children()
+        .filter(|node| node.has_tag_name((DSIG, "Reference")))
+        .collect::<Vec<_>>();
+    let reference = match references[..] {
+        [reference] if reference.attribute("URI") == Some(&format!("#{}", id)) => reference,
+        _ => {
+            return Err(Error::Saml(format!(
+                "The SAML signature does not only sign {}",
+                id
+            )))
+        }
+    };
+
+    // The transforms oktaws supports are removing the signature and canonicalizing what is left
+    let mut canonicalization = None;
+    for transform in child(reference, "Transforms")?
+        .children()
+        .filter(|node| node.is_element())
+    {
+        match algorithm(transform)? {
+            ENVELOPED_SIGNATURE => {}
+            EXC_C14N | EXC_C14N_WITH_COMMENTS => canonicalization = Some(transform),
+            algorithm => return Err(unsupported("transform", algorithm)),
+        }
+    }
+    let canonicalization = canonicalization.ok_or_else(|| {
+        unsupported(
+            "canonicalization",
+            "http://www.w3.org/TR/2001/REC-xml-c14n-20010315",
+        )
+    })?;
+
+    let signed_data = canonicalize(
+        element,
+        Some(signature),
+        &inclusive_prefixes(canonicalization),
+        algorithm(canonicalization)? == EXC_C14N_WITH_COMMENTS,
+    );
+
+    let digest = match algorithm(child(reference, "DigestMethod")?)? {
+        SHA1 => Sha1::digest(signed_data.as_bytes()).to_vec(),
+        SHA256 => Sha256::digest(signed_data.as_bytes()).to_vec(),
+        algorithm => return Err(unsupported("digest", algorithm)),
+    };
+    let expected_digest = base64::decode(strip_whitespace(
+        child(reference, "DigestValue")?.text().unwrap_or(""),
+    ))?;
+
+    if digest != expected_digest {
+        return Err(Error::Saml(
+            "The signed SAML element has been changed since it was signed".to_string(),
+        ));
+    }
+
+    let canonicalization_method = child(signed_info, "CanonicalizationMethod")?;
+    let with_comments = match algorithm(canonicalization_method)? {
+        EXC_C14N => false,
+        EXC_C14N_WITH_COMMENTS => true,
+        algorithm => return Err(unsupported("canonicalization", algorithm)),
+    };
+    let signed_info_data = canonicalize(
+        signed_info,
+        None,
+        &inclusive_prefixes(canonicalization_method),
+        with_comments,
+    );
+
+    let (hash, hashed) = match algorithm(child(signed_info, "SignatureMethod")?)? {
+        RSA_SHA1 => (
+            Hash::SHA1,
+            Sha1::digest(signed_info_data.as_bytes()).to_vec(),
+        ),
+        RSA_SHA256 => (
+            Hash::SHA2_256,
+            Sha256::digest(signed_info_data.as_bytes()).to_vec(),
+        ),
+        algorithm => return Err(unsupported("signature", algorithm)),
+    };
+    let signature_value = base64::decode(strip_whitespace(
+        child(signature, "SignatureValue")?.text().unwrap_or(""),
+    ))?;
+
+    certificate
+        .0
+        .verify(
+            PaddingScheme::PKCS1v15Sign { hash: Some(hash) },
+            &hashed,
+            &signature_value,
+        )
+        .map_err(|_| {
+            Error::Saml("The SAML response is not signed by the configured certificate".to_string())
+        })?;
+
+    Ok(signed_data)
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Result<Node<'a, 'input>, Error> {
+    node.children()
+        .find(|child| child.has_tag_name((DSIG, name)))
+        .ok_or_else(|| Error::Saml(format!("No {} found in the SAML signature", name)))
+}
+
+fn algorithm<'a>(node: Node<'a, '_>) -> Result<&'a str, Error> {
+    node.attribute("Algorithm").ok_or_else(|| {
+        Error::Saml(format!(
+            "No algorithm found for {} in the SAML signature",
+            node.tag_name().name()
+        ))
+    })
+}
+
+/// The prefixes of a canonicalization's `InclusiveNamespaces`
+fn inclusive_prefixes<'a>(node: Node<'a, '_>) -> Vec<&'a str> {
+    node.children()
+        .find(|child| child.has_tag_name((EXC_C14N, "InclusiveNamespaces")))
+        .and_then(|child| child.attribute("PrefixList"))
+        .map(|prefixes| prefixes.split_whitespace().collect())
+        .unwrap_or_default()
+}
+
+fn unsupported(kind: &str, algorithm: &str) -> Error {
+    Error::Saml(format!(
+        "Unsupported {} algorithm in the SAML signature: {}",
+        kind, algorithm
+    ))
+}
+
+fn strip_whitespace(s: &str) -> String {
+    s.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::read_to_string;
+
+    fn certificate() -> Certificate {
+        Certificate::from_file(Path::new("tests/fixtures/saml_certificate.pem")).unwrap()
+    }
+
+    fn signed_response() -> String {
+        read_to_string("tests/fixtures/saml_response_signed.xml").unwrap()
+    }
+
+    #[test]
+    fn read_certificates() {
+        let metadata = read_to_string("tests/fixtures/saml_metadata.xml").unwrap();
+
+        assert_eq!(
+            Certificate::from_metadata(&metadata).unwrap(),
+            certificate()
+        );
+    }
+
+    #[test]
+    fn saml_certificate_config() {
+        assert_eq!(
+            SamlCertificate::from_config(
+                "https://example.okta.com/app/exk1a2b3c4d5e6f7g8h9/sso/saml/metadata",
+                Some(Path::new("/home/user/.oktaws"))
+            )
+            .unwrap(),
+            SamlCertificate::Metadata(
+                "https://example.okta.com/app/exk1a2b3c4d5e6f7g8h9/sso/saml/metadata"
+                    .parse()
+                    .unwrap()
+            )
+        );
+        assert_eq!(
+            SamlCertificate::from_config("okta.cert", Some(Path::new("/home/user/.oktaws")))
+                .unwrap(),
+            SamlCertificate::File(PathBuf::from("/home/user/.oktaws/okta.cert"))
+        );
+
+        let error = SamlCertificate::from_config(
+            "http://example.okta.com/app/exk1a2b3c4d5e6f7g8h9/sso/saml/metadata",
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(error, Error::Config(_)));
+    }
+
+    /// Remove the first signature in `xml`
+    fn remove_signature(xml: &str) -> String {
+        let start = xml.find("<ds:Signature").unwrap();
+        let end = xml.find("</ds:Signature>").unwrap() + "</ds:Signature>".len();
+
+        format!("{}{}", &xml[..start], &xml[end..])
+    }
+
+    #[test]
+    fn verify_signed_responses() {
+        let xml = signed_response();
+        let signed = verify(&xml, &certificate()).unwrap().unwrap();
+        assert!(signed.starts_with("<saml2p:Response "));
+        // Without its own signature, but with the assertion's
+        assert_eq!(signed.matches("<ds:Signature ").count(), 1);
+
+        // Without the response's signature, the assertion's signature is checked instead
+        let assertion_signed = remove_signature(&xml);
+        let signed = verify(&assertion_signed, &certificate()).unwrap().unwrap();
+        assert!(signed.starts_with("<saml2:Assertion "));
+        assert!(!signed.contains("<ds:Signature "));
+
+        let unsigned = remove_signature(&assertion_signed);
+        assert_eq!(verify(&unsigned, &certificate()).unwrap(), None);
+    }
+
+    #[test]
+    fn reject_changed_responses() {
+        let xml = signed_response().replace("role/role2", "role/admin");
+
+        let error = verify(&xml, &certificate()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The signed SAML element has been changed since it was signed"
+        );
+    }
+
+    #[test]
+    fn reject_other_certificates() {
+        // The certificate in the signature, which must not be trusted just because it is there
+        let other = read_to_string("tests/fixtures/saml_response.xml").unwrap();
+        let other = other
+            .split("<ds:X509Certificate>")
+            .nth(1)
+            .and_then(|rest| rest.split("</ds:X509Certificate>").next())
+            .unwrap();
+
+        let error = verify(
+            &signed_response(),
+            &Certificate::from_base64(other).unwrap(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The SAML response is not signed by the configured certificate"
+        );
+    }
+
+    #[test]
+    fn reject_extra_assertions() {
+        let xml = signed_response();
+        let start = xml.find("<saml2:Assertion").unwrap();
+        let end = xml.find("</saml2p:Response>").unwrap();
+        let wrapped = format!("{}{}{}", &xml[..end], &xml[start..end], &xml[end..]);
+
+        assert!(verify(&wrapped, &certificate()).is_err());
+    }
+
+    #[test]
+    fn reject_extra_signatures() {
+        let xml = signed_response();
+        let start = xml.find("<ds:Signature").unwrap();
+        let end = xml.find("</ds:Signature>").unwrap() + "</ds:Signature>".len();
+        let signature = &xml[start..end];
+
+        // A second signature on the response, and a signature elsewhere in it
+        let attribute = xml.find("<saml2:AttributeValue").unwrap();
+        for wrapped in &[
+            format!("{}{}{}", &xml[..end], signature, &xml[end..]),
+            format!("{}{}{}", &xml[..attribute], signature, &xml[attribute..]),
+        ] {
+            assert_eq!(
+                verify(wrapped, &certificate()).unwrap_err().to_string(),
+                "Unexpected signatures found in the SAML response"
+            );
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use crate::cache;
+use crate::error::Error;
+use crate::tty;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use dialoguer::Password;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+
+/// Secrets encrypted with a passphrase, for when no Secret Service is running
+pub struct EncryptedFile {
+    path: PathBuf,
+    /// The passphrase, so it is only asked for once per run
+    passphrase: Mutex<Option<String>>,
+}
+
+/// The encrypted file, with binary values base64-encoded
+#[derive(Deserialize, Serialize)]
+struct Contents {
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+impl EncryptedFile {
+    pub fn new() -> Result<Self, Error> {
+        Ok(EncryptedFile::from(cache::dir()?.join("secrets.json")))
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<String>, Error> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        Ok(self.read()?.remove(name))
+    }
+
+    pub fn set(&self, name: &str, secret: &str) -> Result<(), Error> {
+        let mut secrets = if self.path.exists() {
+            self.read()?
+        } else {
+            HashMap::new()
+        };
+
+        secrets.insert(name.to_string(), secret.to_string());
+        self.write(&secrets)
+    }
+
+    fn read(&self) -> Result<HashMap<String, String>, Error> {
+        let contents: Contents = serde_json::from_reader(File::open(&self.path)?)?;
+        let decode = |value: &str| {
+            base64::decode(value).map_err(|e| {
+                Error::Config(format!(
+                    "Invalid secrets file {}: {}",
+                    self.path.display(),
+                    e
+                ))
+            })
+        };
+
+        let salt = decode(&contents.salt)?;
+        let nonce = Nonce::try_assume_unique_for_key(&decode(&contents.nonce)?)
+            .map_err(|_| Error::Config(format!("Invalid secrets file {}", self.path.display())))?;
+        let mut ciphertext = decode(&contents.ciphertext)?;
+
+        let plaintext = key(&self.passphrase()?, &salt)
+            .open_in_place(nonce, Aad::empty(), &mut ciphertext)
+            .map_err(|_| {
+                // Ask again next time, in case it was mistyped
+                *self.passphrase.lock().unwrap() = None;
+
+                Error::Authentication(format!("Wrong passphrase for {}", self.path.display()))
+            })?;
+
+        serde_json::from_slice(plaintext).map_err(Into::into)
+    }
+
+    fn write(&self, secrets: &HashMap<String, String>) -> Result<(), Error> {
+        let rng = SystemRandom::new();
+        let mut salt = [0; SALT_LEN];
+        let mut nonce = [0; NONCE_LEN];
+        rng.fill(&mut salt)
+            .and_then(|_| rng.fill(&mut nonce))
+            .map_err(|_| Error::Config("Could not generate a random key".to_string()))?;
+
+        let mut ciphertext = serde_json::to_vec(secrets)?;
+        key(&self.passphrase()?, &salt)
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut ciphertext,
+            )
+            .map_err(|_| Error::Config("Could not encrypt secrets".to_string()))?;
+
+        cache::write(
+            &self.path,
+            &Contents {
+                salt: base64::encode(salt),
+                nonce: base64::encode(nonce),
+                ciphertext: base64::encode(ciphertext),
+            },
+        )
+    }
+
+    fn passphrase(&self) -> Result<String, Error> {
+        if let Some(ref passphrase) = *self.passphrase.lock().unwrap() {
+            return Ok(passphrase.clone());
+        }
+
+        tty::require("the passphrase of the credentials file")?;
+
+        let mut prompt = Password::new();
+        prompt.with_prompt(format!("Passphrase for {}", self.path.display()));
+        if !self.path.exists() {
+            prompt.with_confirmation("Repeat passphrase", "Passphrases do not match");
+        }
+
+        let passphrase = prompt.interact()?;
+        *self.passphrase.lock().unwrap() = Some(passphrase.clone());
+
+        Ok(passphrase)
+    }
+}
+
+impl From<PathBuf> for EncryptedFile {
+    fn from(path: PathBuf) -> Self {
+        EncryptedFile {
+            path,
+            passphrase: Mutex::new(None),
+        }
+    }
+}
+
+/// The encryption key for a passphrase
+fn key(passphrase: &str, salt: &[u8]) -> LessSafeKey {
+    let mut key = [0; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    fn with_passphrase(path: PathBuf, passphrase: &str) -> EncryptedFile {
+        let file = EncryptedFile::from(path);
+        *file.passphrase.lock().unwrap() = Some(passphrase.to_string());
+        file
+    }
+
+    #[test]
+    fn encrypted_roundtrip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("secrets.json");
+
+        let file = with_passphrase(path.clone(), "correct horse");
+        assert_eq!(file.get("mock_org").unwrap(), None);
+
+        file.set("mock_org", "hunter2").unwrap();
+        file.set("other_org", "swordfish").unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("hunter2"));
+
+        let file = with_passphrase(path, "correct horse");
+        assert_eq!(file.get("mock_org").unwrap().as_deref(), Some("hunter2"));
+        assert_eq!(file.get("other_org").unwrap().as_deref(), Some("swordfish"));
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("secrets.json");
+
+        with_passphrase(path.clone(), "correct horse")
+            .set("mock_org", "hunter2")
+            .unwrap();
+
+        let file = with_passphrase(path, "battery staple");
+        assert!(matches!(
+            file.get("mock_org"),
+            Err(Error::Authentication(_))
+        ));
+        assert!(file.passphrase.lock().unwrap().is_none());
+    }
+}
@@ -0,0 +1,97 @@
+#[cfg(target_os = "linux")]
+mod file;
+#[cfg(target_os = "linux")]
+mod secret_service;
+
+use crate::error::Error;
+#[cfg(target_os = "linux")]
+use crate::secrets::file::EncryptedFile;
+#[cfg(target_os = "linux")]
+use crate::secrets::secret_service::SecretService;
+
+#[cfg(target_os = "linux")]
+use std::collections::HashMap;
+
+#[cfg(target_os = "linux")]
+use once_cell::sync::OnceCell;
+
+#[cfg(not(target_os = "linux"))]
+use keyring::Keyring;
+
+/// A password or other secret kept in the user's keyring between runs.
+///
+/// On Linux this is the Secret Service (GNOME Keyring, KWallet, ...), or a file encrypted with a
+/// passphrase when no Secret Service is running.
+pub struct KeyringEntry {
+    service: String,
+    username: String,
+}
+
+/// The encrypted file, shared by every entry so that its passphrase is only asked for once per run
+#[cfg(target_os = "linux")]
+static FALLBACK: OnceCell<EncryptedFile> = OnceCell::new();
+
+impl KeyringEntry {
+    pub fn new(service: &str, username: &str) -> Self {
+        KeyringEntry {
+            service: service.to_string(),
+            username: username.to_string(),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn get(&mut self) -> Result<Option<String>, Error> {
+        Ok(Keyring::new(&self.service, &self.username)
+            .get_password()
+            .ok())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn set(&mut self, secret: &str) -> Result<(), Error> {
+        Keyring::new(&self.service, &self.username)
+            .set_password(secret)
+            .map_err(|e| Error::Config(e.to_string()))
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn get(&mut self) -> Result<Option<String>, Error> {
+        match SecretService::connect() {
+            Ok(secret_service) => secret_service.get(&self.attributes()),
+            Err(e) => {
+                debug!("Using an encrypted file, as {}", e);
+                fallback()?.get(&self.name())
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn set(&mut self, secret: &str) -> Result<(), Error> {
+        match SecretService::connect() {
+            Ok(secret_service) => secret_service.set(&self.name(), &self.attributes(), secret),
+            Err(e) => {
+                debug!("Using an encrypted file, as {}", e);
+                fallback()?.set(&self.name(), secret)
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn name(&self) -> String {
+        format!("{}@{}", self.username, self.service)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn attributes(&self) -> HashMap<&str, &str> {
+        let mut attributes = HashMap::new();
+        attributes.insert("application", "oktaws");
+        attributes.insert("service", self.service.as_str());
+        attributes.insert("username", self.username.as_str());
+        attributes
+    }
+}
+
+/// The encrypted file, for when no Secret Service is running
+#[cfg(target_os = "linux")]
+fn fallback() -> Result<&'static EncryptedFile, Error> {
+    FALLBACK.get_or_try_init(EncryptedFile::new)
+}
@@ -0,0 +1,175 @@
+use crate::error::Error;
+
+use std::collections::HashMap;
+
+use zbus::{Connection, Message};
+use zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value};
+
+const DESTINATION: &str = "org.freedesktop.secrets";
+const SERVICE_PATH: &str = "/org/freedesktop/secrets";
+const DEFAULT_COLLECTION: &str = "/org/freedesktop/secrets/aliases/default";
+
+const SERVICE_INTERFACE: &str = "org.freedesktop.Secret.Service";
+const COLLECTION_INTERFACE: &str = "org.freedesktop.Secret.Collection";
+const ITEM_INTERFACE: &str = "org.freedesktop.Secret.Item";
+const PROMPT_INTERFACE: &str = "org.freedesktop.Secret.Prompt";
+
+/// The path the Secret Service returns when no prompt is needed
+const NO_PROMPT: &str = "/";
+
+/// A secret as sent over D-Bus: the session, parameters, value and content type
+type Secret = (OwnedObjectPath, Vec<u8>, Vec<u8>, String);
+
+/// A session with the Secret Service (as provided by GNOME Keyring or KWallet) on the session bus
+pub struct SecretService {
+    connection: Connection,
+    session: OwnedObjectPath,
+}
+
+impl SecretService {
+    pub fn connect() -> Result<Self, Error> {
+        let connection = Connection::new_session().map_err(to_error)?;
+
+        // Secrets are sent unencrypted, as they never leave the session bus
+        let reply = connection
+            .call_method(
+                Some(DESTINATION),
+                SERVICE_PATH,
+                Some(SERVICE_INTERFACE),
+                "OpenSession",
+                &("plain", Value::from("")),
+            )
+            .map_err(to_error)?;
+        let (_, session): (OwnedValue, OwnedObjectPath) = body(&reply)?;
+
+        Ok(SecretService {
+            connection,
+            session,
+        })
+    }
+
+    /// The secret of the item with these attributes, unlocking it if needed
+    pub fn get(&self, attributes: &HashMap<&str, &str>) -> Result<Option<String>, Error> {
+        let reply = self.call(SERVICE_PATH, SERVICE_INTERFACE, "SearchItems", attributes)?;
+        let (unlocked, locked): (Vec<OwnedObjectPath>, Vec<OwnedObjectPath>) = body(&reply)?;
+
+        let item = match (unlocked.into_iter().next(), locked.into_iter().next()) {
+            (Some(item), _) => item,
+            (None, Some(item)) => {
+                let reply =
+                    self.call(SERVICE_PATH, SERVICE_INTERFACE, "Unlock", &(vec![&item],))?;
+                let (_, prompt): (Vec<OwnedObjectPath>, OwnedObjectPath) = body(&reply)?;
+                self.prompt(&prompt)?;
+
+                item
+            }
+            (None, None) => return Ok(None),
+        };
+
+        let reply = self.call(
+            item.as_str(),
+            ITEM_INTERFACE,
+            "GetSecret",
+            &(&self.session,),
+        )?;
+        let (_, _, value, _): Secret = body(&reply)?;
+
+        String::from_utf8(value)
+            .map(Some)
+            .map_err(|e| Error::Config(format!("Invalid secret in the Secret Service: {}", e)))
+    }
+
+    /// Save a secret in the default collection, replacing any item with the same attributes
+    pub fn set(
+        &self,
+        label: &str,
+        attributes: &HashMap<&str, &str>,
+        secret: &str,
+    ) -> Result<(), Error> {
+        let mut properties = HashMap::new();
+        properties.insert("org.freedesktop.Secret.Item.Label", Value::from(label));
+        properties.insert(
+            "org.freedesktop.Secret.Item.Attributes",
+            Value::from(attributes.clone()),
+        );
+
+        let secret: Secret = (
+            self.session.clone(),
+            Vec::new(),
+            secret.as_bytes().to_vec(),
+            "text/plain".to_string(),
+        );
+
+        let reply = self.call(
+            DEFAULT_COLLECTION,
+            COLLECTION_INTERFACE,
+            "CreateItem",
+            &(properties, secret, true),
+        )?;
+        let (_, prompt): (OwnedObjectPath, OwnedObjectPath) = body(&reply)?;
+
+        self.prompt(&prompt)
+    }
+
+    /// Have the Secret Service ask the user to unlock their keyring, if it needs to
+    fn prompt(&self, prompt: &ObjectPath) -> Result<(), Error> {
+        if prompt.as_str() == NO_PROMPT {
+            return Ok(());
+        }
+
+        let rule = format!(
+            "type='signal',interface='{}',member='Completed',path='{}'",
+            PROMPT_INTERFACE,
+            prompt.as_str()
+        );
+        self.connection
+            .call_method(
+                Some("org.freedesktop.DBus"),
+                "/org/freedesktop/DBus",
+                Some("org.freedesktop.DBus"),
+                "AddMatch",
+                &(rule,),
+            )
+            .map_err(to_error)?;
+
+        self.call(prompt.as_str(), PROMPT_INTERFACE, "Prompt", &("",))?;
+
+        let completed = self
+            .connection
+            .receive_specific(|message| {
+                let header = message.header()?;
+                Ok(header.member()? == Some("Completed")
+                    && header.path()?.map(|path| path.as_str()) == Some(prompt.as_str()))
+            })
+            .map_err(to_error)?;
+        let (dismissed, _): (bool, OwnedValue) = body(&completed)?;
+
+        if dismissed {
+            Err(Error::Config(
+                "Unlocking the Secret Service was dismissed".to_string(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn call<B>(&self, path: &str, interface: &str, method: &str, body: &B) -> Result<Message, Error>
+    where
+        B: serde::Serialize + zvariant::Type,
+    {
+        self.connection
+            .call_method(Some(DESTINATION), path, Some(interface), method, body)
+            .map_err(to_error)
+    }
+}
+
+fn body<'m, B>(message: &'m Message) -> Result<B, Error>
+where
+    B: serde::Deserialize<'m> + zvariant::Type,
+{
+    message.body().map_err(|e| to_error(e.into()))
+}
+
+fn to_error(e: zbus::Error) -> Error {
+    Error::Config(format!("Secret Service error: {}", e))
+}
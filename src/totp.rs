@@ -0,0 +1,186 @@
+use crate::error::Error;
+use crate::secrets::KeyringEntry;
+use crate::tty;
+
+use std::convert::TryInto;
+use std::env::var as env_var;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dialoguer::Password;
+use ring::hmac;
+
+/// How long each passcode is valid for, in seconds
+const TIME_STEP: u64 = 30;
+const DIGITS: u32 = 6;
+
+/// Where to read the base32-encoded secret of a TOTP factor from
+#[derive(Clone, Debug, PartialEq)]
+pub enum TotpSecret {
+    Keyring { service: String, username: String },
+    File(PathBuf),
+    Env(String),
+}
+
+impl TotpSecret {
+    /// Parse a `totp_secret` setting: `keyring`, `file:<path>` (relative to `dir`) or
+    /// `env:<variable>`
+    pub fn from_config(
+        value: &str,
+        dir: Option<&Path>,
+        organization: &str,
+        username: &str,
+    ) -> Result<Self, Error> {
+        match value.split_once(':') {
+            None if value == "keyring" => Ok(TotpSecret::Keyring {
+                service: format!("oktaws::totp::{}", organization),
+                username: username.to_string(),
+            }),
+            Some(("file", path)) => Ok(TotpSecret::File(match dir {
+                Some(dir) => dir.join(path),
+                None => PathBuf::from(path),
+            })),
+            Some(("env", variable)) => Ok(TotpSecret::Env(variable.to_string())),
+            _ => Err(Error::Config(format!(
+                "Unknown TOTP secret {} (expected keyring, file:<path> or env:<variable>)",
+                value
+            ))),
+        }
+    }
+
+    /// The passcode for the current time
+    pub fn passcode(&self) -> Result<String, Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| Error::MfaRejected(e.to_string()))?;
+
+        passcode(&self.read()?, now.as_secs())
+    }
+
+    fn read(&self) -> Result<String, Error> {
+        match self {
+            TotpSecret::Keyring { service, username } => {
+                let mut keyring = KeyringEntry::new(service, username);
+
+                match keyring.get()? {
+                    Some(secret) => Ok(secret),
+                    None => {
+                        // Ask once, so that later runs can be unattended
+                        tty::require("the TOTP secret")?;
+
+                        let secret = Password::new()
+                            .with_prompt(format!("TOTP secret for {}", username))
+                            .interact()?;
+
+                        if let Err(e) = keyring.set(&secret) {
+                            warn!("Error while saving TOTP secret: {}", e);
+                        }
+
+                        Ok(secret)
+                    }
+                }
+            }
+            TotpSecret::File(path) => read_to_string(path)
+                .map(|secret| secret.trim().to_string())
+                .map_err(|e| {
+                    Error::Config(format!(
+                        "Could not read TOTP secret from {}: {}",
+                        path.display(),
+                        e
+                    ))
+                }),
+            TotpSecret::Env(variable) => env_var(variable).map_err(|_| {
+                Error::Config(format!(
+                    "The environment variable {} must be set to the TOTP secret",
+                    variable
+                ))
+            }),
+        }
+    }
+}
+
+/// The RFC 6238 passcode for a base32-encoded `secret`, `time` seconds after the epoch
+pub fn passcode(secret: &str, time: u64) -> Result<String, Error> {
+    // Secrets are often shown in groups of four, in either case, and sometimes padded
+    let secret = secret.replace(' ', "").to_uppercase();
+    let secret = secret.trim_end_matches('=');
+    let key = base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)
+        .ok_or_else(|| Error::Config("The TOTP secret is not valid base32".to_string()))?;
+
+    let counter = time / TIME_STEP;
+    let tag = hmac::sign(
+        &hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, &key),
+        &counter.to_be_bytes(),
+    );
+    let digest = tag.as_ref();
+
+    // Dynamic truncation, as in RFC 4226
+    let offset = (digest[digest.len() - 1] & 0xf) as usize;
+    let code = u32::from_be_bytes(digest[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+
+    Ok(format!(
+        "{:0width$}",
+        code % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The SHA-1 secret from RFC 6238 ("12345678901234567890"), base32-encoded
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn rfc6238_passcodes() {
+        assert_eq!(passcode(SECRET, 59).unwrap(), "287082");
+        assert_eq!(passcode(SECRET, 1111111109).unwrap(), "081804");
+        assert_eq!(passcode(SECRET, 1234567890).unwrap(), "005924");
+        assert_eq!(passcode(SECRET, 2000000000).unwrap(), "279037");
+    }
+
+    #[test]
+    fn passcode_ignores_case_and_spaces() {
+        assert_eq!(
+            passcode("gezd gnbv gy3t qojq gezd gnbv gy3t qojq", 59).unwrap(),
+            "287082"
+        );
+        assert_eq!(
+            passcode("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGE======", 59).unwrap(),
+            passcode("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGE", 59).unwrap()
+        );
+        assert!(passcode("not base32!", 59).is_err());
+    }
+
+    #[test]
+    fn parse_secret() {
+        assert_eq!(
+            TotpSecret::from_config("env:TOTP_SECRET", None, "mock_org", "mock_user").unwrap(),
+            TotpSecret::Env("TOTP_SECRET".to_string())
+        );
+        assert_eq!(
+            TotpSecret::from_config("file:/tmp/secret", None, "mock_org", "mock_user").unwrap(),
+            TotpSecret::File(PathBuf::from("/tmp/secret"))
+        );
+        assert_eq!(
+            TotpSecret::from_config(
+                "file:secrets/totp",
+                Some(Path::new("/home/user/.oktaws")),
+                "mock_org",
+                "mock_user"
+            )
+            .unwrap(),
+            TotpSecret::File(PathBuf::from("/home/user/.oktaws/secrets/totp"))
+        );
+        assert_eq!(
+            TotpSecret::from_config("keyring", None, "mock_org", "mock_user").unwrap(),
+            TotpSecret::Keyring {
+                service: "oktaws::totp::mock_org".to_string(),
+                username: "mock_user".to_string(),
+            }
+        );
+        assert!(TotpSecret::from_config("vault:secret", None, "mock_org", "mock_user").is_err());
+    }
+}
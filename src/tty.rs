@@ -0,0 +1,19 @@
+use crate::error::Error;
+
+/// Fail before asking for `what` when there is no terminal to answer on, as the prompt would wait
+/// forever for input that is never coming
+pub fn require(what: &str) -> Result<(), Error> {
+    if is_tty() {
+        Ok(())
+    } else {
+        Err(Error::Config(format!(
+            "Cannot ask for {} without a TTY; configure `factor` and `role`, and save your credentials by running oktaws from a terminal once",
+            what
+        )))
+    }
+}
+
+/// Whether the user can be asked questions
+pub fn is_tty() -> bool {
+    atty::is(atty::Stream::Stdin)
+}